@@ -0,0 +1,210 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use aspotify::Album;
+use sanitize_filename::sanitize;
+
+use crate::error::SpotifyError;
+
+/// Max albums kept in memory
+const MAX_ALBUMS: usize = 256;
+/// Max cover bytes kept in memory
+const MAX_COVER_BYTES: usize = 64 * 1024 * 1024;
+/// Max album disc counts kept in memory
+const MAX_DISC_TOTALS: usize = 4096;
+
+/// Album metadata and cover cache shared between jobs
+#[derive(Debug, Clone)]
+pub struct Cache {
+    albums: Arc<Mutex<Bounded<Album>>>,
+    covers: Arc<Mutex<Bounded<(String, Vec<u8>)>>>,
+    disc_totals: Arc<Mutex<Bounded<usize>>>,
+    // Prevent concurrent fetches of same key
+    in_flight: Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>,
+    folder: Option<PathBuf>
+}
+
+impl Cache {
+    /// Create new instance
+    pub fn new() -> Cache {
+        Cache::with_folder(Cache::get_folder())
+    }
+
+    /// Create new instance with custom on disk folder, None keeps everything in memory
+    pub fn with_folder(folder: Option<PathBuf>) -> Cache {
+        Cache {
+            albums: Arc::new(Mutex::new(Bounded::new(MAX_ALBUMS, |_| 1))),
+            covers: Arc::new(Mutex::new(Bounded::new(MAX_COVER_BYTES, |(_, data)| data.len()))),
+            disc_totals: Arc::new(Mutex::new(Bounded::new(MAX_DISC_TOTALS, |_| 1))),
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+            folder
+        }
+    }
+
+    /// Get on disk cache folder
+    pub fn get_folder() -> Option<PathBuf> {
+        Some(dirs::cache_dir()?.join("spotidown"))
+    }
+
+    /// Get album from cache or fetch it
+    pub async fn album<F, Fut>(&self, id: &str, persist: bool, fetch: F) -> Result<Album, SpotifyError>
+    where F: FnOnce() -> Fut, Fut: std::future::Future<Output = Result<Album, SpotifyError>> {
+        let key = format!("album:{}", id);
+        let lock = self.lock(&key);
+        let _guard = lock.lock().await;
+
+        if let Some(album) = self.albums.lock().unwrap().get(&key) {
+            return Ok(album);
+        }
+        // Disk
+        let path = self.folder.as_ref().map(|f| f.join("albums").join(format!("{}.json", sanitize(id))));
+        if let (true, Some(path)) = (persist, &path) {
+            if let Ok(data) = tokio::fs::read(path).await {
+                if let Ok(album) = serde_json::from_slice::<Album>(&data) {
+                    self.albums.lock().unwrap().insert(key, album.clone());
+                    return Ok(album);
+                }
+            }
+        }
+
+        let album = fetch().await?;
+        self.albums.lock().unwrap().insert(key, album.clone());
+        if let (true, Some(path)) = (persist, &path) {
+            if let Err(e) = Cache::write(path, serde_json::to_vec(&album)?).await {
+                warn!("Failed saving album {} to cache: {}", id, e);
+            }
+        }
+        Ok(album)
+    }
+
+    /// Get album from memory cache only
+    pub fn get_album(&self, id: &str) -> Option<Album> {
+        self.albums.lock().unwrap().get(&format!("album:{}", id))
+    }
+
+    /// Add prefetched album to memory cache
+    pub fn insert_album(&self, album: Album) {
+        self.albums.lock().unwrap().insert(format!("album:{}", album.id), album);
+    }
+
+    /// Get disc count of album from cache or compute it
+    pub async fn disc_total<F, Fut>(&self, id: &str, fetch: F) -> Result<usize, SpotifyError>
+    where F: FnOnce() -> Fut, Fut: std::future::Future<Output = Result<usize, SpotifyError>> {
+        let key = format!("discs:{}", id);
+        let lock = self.lock(&key);
+        let _guard = lock.lock().await;
+
+        if let Some(total) = self.disc_totals.lock().unwrap().get(&key) {
+            return Ok(total);
+        }
+        let total = fetch().await?;
+        self.disc_totals.lock().unwrap().insert(key, total);
+        Ok(total)
+    }
+
+    /// Get cover from cache or fetch it, returns mime and data
+    pub async fn cover<F, Fut>(&self, url: &str, persist: bool, fetch: F) -> Result<(String, Vec<u8>), SpotifyError>
+    where F: FnOnce() -> Fut, Fut: std::future::Future<Output = Result<(String, Vec<u8>), SpotifyError>> {
+        let key = format!("cover:{}", url);
+        let lock = self.lock(&key);
+        let _guard = lock.lock().await;
+
+        if let Some(cover) = self.covers.lock().unwrap().get(&key) {
+            return Ok(cover);
+        }
+        // Disk, mime is stored in separate file
+        let path = self.folder.as_ref().map(|f| f.join("covers").join(sanitize(url.rsplit('/').next().unwrap_or(url))));
+        if let (true, Some(path)) = (persist, &path) {
+            if let (Ok(mime), Ok(data)) = (tokio::fs::read_to_string(path.with_extension("mime")).await, tokio::fs::read(path).await) {
+                self.covers.lock().unwrap().insert(key, (mime.clone(), data.clone()));
+                return Ok((mime, data));
+            }
+        }
+
+        let (mime, data) = fetch().await?;
+        self.covers.lock().unwrap().insert(key, (mime.clone(), data.clone()));
+        if let (true, Some(path)) = (persist, &path) {
+            if let Err(e) = Cache::write(path, data.clone()).await {
+                warn!("Failed saving cover to cache: {}", e);
+            }
+            Cache::write(&path.with_extension("mime"), mime.as_bytes().to_vec()).await.ok();
+        }
+        Ok((mime, data))
+    }
+
+    /// Get per key fetch lock
+    fn lock(&self, key: &str) -> Arc<tokio::sync::Mutex<()>> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        // Remove unused locks
+        in_flight.retain(|_, l| Arc::strong_count(l) > 1);
+        in_flight.entry(key.to_string()).or_insert(Arc::new(tokio::sync::Mutex::new(()))).clone()
+    }
+
+    /// Write file, create parent dirs
+    async fn write(path: &PathBuf, data: Vec<u8>) -> Result<(), SpotifyError> {
+        tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+        tokio::fs::write(path, data).await?;
+        Ok(())
+    }
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Cache::new()
+    }
+}
+
+/// Size bounded map, oldest entries are evicted first
+#[derive(Debug)]
+struct Bounded<V> {
+    map: HashMap<String, V>,
+    order: VecDeque<String>,
+    size: usize,
+    max_size: usize,
+    weight: fn(&V) -> usize
+}
+
+impl<V: Clone> Bounded<V> {
+    fn new(max_size: usize, weight: fn(&V) -> usize) -> Bounded<V> {
+        Bounded {
+            map: HashMap::new(),
+            order: VecDeque::new(),
+            size: 0,
+            max_size,
+            weight
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<V> {
+        let value = self.map.get(key)?.clone();
+        // Move to back
+        if let Some(i) = self.order.iter().position(|k| k == key) {
+            let k = self.order.remove(i).unwrap();
+            self.order.push_back(k);
+        }
+        Some(value)
+    }
+
+    fn insert(&mut self, key: String, value: V) {
+        let weight = (self.weight)(&value);
+        if weight > self.max_size {
+            return;
+        }
+        if let Some(old) = self.map.remove(&key) {
+            self.size -= (self.weight)(&old);
+            self.order.retain(|k| k != &key);
+        }
+        // Evict
+        while self.size + weight > self.max_size {
+            match self.order.pop_front() {
+                Some(k) => if let Some(old) = self.map.remove(&k) {
+                    self.size -= (self.weight)(&old);
+                },
+                None => break
+            }
+        }
+        self.size += weight;
+        self.order.push_back(key.clone());
+        self.map.insert(key, value);
+    }
+}
@@ -0,0 +1,293 @@
+use serde_json::Value;
+
+use crate::downloader::{DownloadOverrides, DownloaderConfig};
+use crate::error::SpotifyError;
+
+/// Environment variable prefix
+const ENV_PREFIX: &str = "SPOTIDOWN_";
+/// Keys which aren't downloader config fields
+const CREDENTIAL_KEYS: [&str; 5] = ["username", "password", "client_id", "client_secret", "passphrase"];
+/// Keys which are never printed
+const SECRET_KEYS: [&str; 3] = ["password", "client_secret", "passphrase"];
+/// Env vars handled elsewhere
+const IGNORED_ENV: [&str; 1] = ["LOG"];
+
+/// Where config value came from, later layers win
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Layer {
+    Default, File, Env, Cli
+}
+
+impl ToString for Layer {
+    fn to_string(&self) -> String {
+        match self {
+            Layer::Default => "default",
+            Layer::File => "settings file",
+            Layer::Env => "environment",
+            Layer::Cli => "command line",
+        }.to_string()
+    }
+}
+
+/// Values from SPOTIDOWN_* environment variables and command line flags, applied over settings file
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    /// Key, raw value and layer
+    values: Vec<(String, String, Layer)>,
+    /// URIs to queue on start with their overrides
+    pub queue: Vec<(String, Option<DownloadOverrides>)>,
+    /// Print effective config and exit
+    pub show_config: bool,
+    pub help: bool
+}
+
+impl Overrides {
+    /// Read from process environment and arguments
+    pub fn from_env() -> Result<Overrides, SpotifyError> {
+        Overrides::parse(std::env::vars(), std::env::args().skip(1))
+    }
+
+    /// Parse env vars and arguments, `--key value` or `--key=value`
+    pub fn parse(vars: impl Iterator<Item = (String, String)>, args: impl Iterator<Item = String>) -> Result<Overrides, SpotifyError> {
+        let keys = Overrides::keys();
+        let mut overrides = Overrides::default();
+        for (name, value) in vars {
+            let key = match name.strip_prefix(ENV_PREFIX) {
+                Some(key) if !IGNORED_ENV.contains(&key) => key.to_lowercase(),
+                _ => continue
+            };
+            match keys.contains(&key) {
+                true => overrides.values.push((key, value, Layer::Env)),
+                false => warn!("Unknown config environment variable: {}", name)
+            }
+        }
+
+        let mut args = args;
+        // Item overrides for next --add
+        let mut item = DownloadOverrides::default();
+        while let Some(arg) = args.next() {
+            let flag = arg.strip_prefix("--").ok_or(SpotifyError::Error(format!("Unexpected argument: {}", arg)))?;
+            let (key, value) = match flag.split_once('=') {
+                Some((key, value)) => (key.replace('-', "_"), Some(value.to_string())),
+                None => (flag.replace('-', "_"), None)
+            };
+            let mut next_value = || match value.clone() {
+                Some(value) => Ok(value),
+                None => args.next().ok_or(SpotifyError::Error(format!("Missing value for --{}", flag)))
+            };
+            match key.as_str() {
+                "show_config" => overrides.show_config = true,
+                "help" => overrides.help = true,
+                "add" => {
+                    let item = std::mem::take(&mut item);
+                    overrides.queue.push((next_value()?, Some(item).filter(|i| !i.is_empty())));
+                }
+                _ if keys.contains(&key) => overrides.values.push((key, next_value()?, Layer::Cli)),
+                _ => match key.strip_prefix("item_") {
+                    Some(item_key) if DownloadOverrides::KEYS.contains(&item_key) => item.set(item_key, &next_value()?)?,
+                    _ => return Err(SpotifyError::Error(format!("Unknown option: --{}", flag)))
+                }
+            }
+        }
+        if !item.is_empty() {
+            return Err(SpotifyError::Error("--item-* options must be followed by --add".into()));
+        }
+        // Command line wins over environment
+        overrides.values.sort_by_key(|(_, _, layer)| *layer);
+        Ok(overrides)
+    }
+
+    /// All supported keys
+    fn keys() -> Vec<String> {
+        let mut keys = Overrides::config_keys();
+        keys.extend(CREDENTIAL_KEYS.iter().map(|k| k.to_string()));
+        keys
+    }
+
+    /// Downloader config fields
+    fn config_keys() -> Vec<String> {
+        match serde_json::to_value(DownloaderConfig::fallback()) {
+            Ok(Value::Object(map)) => map.keys().cloned().collect(),
+            _ => vec![]
+        }
+    }
+
+    /// Get value and its layer
+    pub fn get(&self, key: &str) -> Option<(&str, Layer)> {
+        self.values.iter().rev().find(|(k, _, _)| k == key).map(|(_, v, l)| (v.as_str(), *l))
+    }
+
+    /// Username, password, client ID and secret if all are set
+    pub fn credentials(&self) -> Option<(String, String, String, String)> {
+        let get = |key| self.get(key).map(|(v, _)| v.to_string());
+        Some((get("username")?, get("password")?, get("client_id")?, get("client_secret")?))
+    }
+
+    /// Apply overrides to downloader config, values are JSON or plain strings
+    pub fn apply(&self, config: &DownloaderConfig) -> Result<DownloaderConfig, SpotifyError> {
+        let mut value = serde_json::to_value(config)?;
+        for (key, raw, _) in &self.values {
+            if let Some(field) = value.get_mut(key) {
+                *field = match field {
+                    Value::String(_) => Value::String(raw.to_string()),
+                    _ => serde_json::from_str(raw).unwrap_or(Value::String(raw.to_string()))
+                };
+            }
+        }
+        serde_json::from_value(value).map_err(|e| SpotifyError::Error(format!("Invalid config override: {}", e)))
+    }
+
+    /// Undo overrides before saving, values changed in UI are kept
+    pub fn unapply(&self, config: &DownloaderConfig, file: &DownloaderConfig) -> Result<DownloaderConfig, SpotifyError> {
+        let overridden = serde_json::to_value(self.apply(file)?)?;
+        let file = serde_json::to_value(file)?;
+        let mut value = serde_json::to_value(config)?;
+        for (key, _, _) in &self.values {
+            if value.get(key).is_some() && value[key] == overridden[key] {
+                value[key] = file[key].clone();
+            }
+        }
+        Ok(serde_json::from_value(value)?)
+    }
+
+    /// Describe effective value and layer of every key,
+    /// file is raw downloader config and account from settings file
+    pub fn describe(&self, config: &DownloaderConfig, file_config: Option<&Value>, file_account: Option<&Value>) -> Result<String, SpotifyError> {
+        let effective = serde_json::to_value(self.apply(config)?)?;
+        let mut out = String::new();
+        for key in Overrides::config_keys() {
+            let layer = match self.get(&key) {
+                Some((_, layer)) => layer,
+                None if file_config.and_then(|c| c.get(&key)).is_some() => Layer::File,
+                None => Layer::Default
+            };
+            out.push_str(&format!("{} = {} ({})\n", key, effective[&key], layer.to_string()));
+        }
+        for key in CREDENTIAL_KEYS.iter() {
+            let (value, layer) = match (self.get(key), file_account.and_then(|a| a.get(key))) {
+                (Some(_), _) | (None, Some(_)) if SECRET_KEYS.contains(key) => {
+                    ("<hidden>".to_string(), self.get(key).map(|(_, l)| l).unwrap_or(Layer::File))
+                }
+                (Some((value, layer)), _) => (value.to_string(), layer),
+                (None, Some(value)) => (value.to_string(), Layer::File),
+                // Password and client secret of saved account are in secret store
+                (None, None) => match (*key, file_account) {
+                    ("password", Some(_)) | ("client_secret", Some(_)) => ("<stored>".to_string(), Layer::File),
+                    _ => ("<unset>".to_string(), Layer::Default)
+                }
+            };
+            out.push_str(&format!("{} = {} ({})\n", key, value, layer.to_string()));
+        }
+        Ok(out)
+    }
+
+    /// Command line usage
+    pub fn usage() -> String {
+        let mut out = "Usage: spotidown [--show-config] [--<key> <value>]... [[--item-<key> <value>]... --add <uri>]...\n\n".to_string();
+        out.push_str("Keys, also read from SPOTIDOWN_<KEY> environment variables:\n");
+        for key in Overrides::keys() {
+            out.push_str(&format!("  --{}\n", key.replace('_', "-")));
+        }
+        out.push_str("\nPer item keys, apply to next --add:\n");
+        for key in DownloadOverrides::KEYS.iter() {
+            out.push_str(&format!("  --item-{}\n", key.replace('_', "-")));
+        }
+        out.push_str("\nValues are JSON or plain strings. Command line wins over environment, environment over settings file.\n");
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use serde_json::json;
+
+    use crate::downloader::{DownloadOverrides, DownloaderConfig};
+    use super::{Layer, Overrides};
+
+    fn parse(vars: &[(&str, &str)], args: &[&str]) -> Result<Overrides, crate::error::SpotifyError> {
+        Overrides::parse(
+            vars.iter().map(|(k, v)| (k.to_string(), v.to_string())),
+            args.iter().map(|a| a.to_string())
+        )
+    }
+
+    #[test]
+    fn parse_layers_and_items() {
+        let vars = [("SPOTIDOWN_CONCURRENT_DOWNLOADS", "2"), ("SPOTIDOWN_QUALITY", "Q160"), ("SPOTIDOWN_LOG", "debug"), ("HOME", "/root")];
+        let args = [
+            "--concurrent-downloads", "3", "--item-priority", "5", "--item-path=/music/a",
+            "--add", "spotify:track:a", "--add=spotify:track:b"
+        ];
+        let overrides = parse(&vars, &args).unwrap();
+        // Command line wins over environment
+        assert_eq!(overrides.get("concurrent_downloads"), Some(("3", Layer::Cli)));
+        assert_eq!(overrides.get("quality"), Some(("Q160", Layer::Env)));
+        assert_eq!(overrides.get("log"), None);
+        // Item overrides apply only to next --add
+        let item = DownloadOverrides { path: Some(PathBuf::from("/music/a")), priority: Some(5), ..Default::default() };
+        assert_eq!(overrides.queue, vec![
+            ("spotify:track:a".to_string(), Some(item)),
+            ("spotify:track:b".to_string(), None)
+        ]);
+    }
+
+    #[test]
+    fn parse_errors() {
+        // Item overrides without --add
+        assert!(parse(&[], &["--add", "spotify:track:a", "--item-priority", "1"]).is_err());
+        assert!(parse(&[], &["--item-priority", "high", "--add", "spotify:track:a"]).is_err());
+        assert!(parse(&[], &["--unknown", "1"]).is_err());
+        assert!(parse(&[], &["--quality"]).is_err());
+        assert!(parse(&[], &["quality"]).is_err());
+    }
+
+    #[test]
+    fn apply_json_values() {
+        let args = ["--concurrent-downloads", "3", "--convert-to-mp3", "true", "--path", "/music", "--filename-template", "42"];
+        let config = parse(&[], &args).unwrap().apply(&DownloaderConfig::fallback()).unwrap();
+        assert_eq!(config.concurrent_downloads, 3);
+        assert!(config.convert_to_mp3);
+        assert_eq!(config.path, PathBuf::from("/music"));
+        // String fields aren't parsed as JSON
+        assert_eq!(config.filename_template, "42");
+
+        let overrides = parse(&[], &["--concurrent-downloads", "many"]).unwrap();
+        assert!(overrides.apply(&DownloaderConfig::fallback()).is_err());
+    }
+
+    #[test]
+    fn unapply_keeps_ui_changes() {
+        let file = DownloaderConfig::fallback();
+        let overrides = parse(&[("SPOTIDOWN_QUALITY", "Q96")], &["--concurrent-downloads", "3", "--path", "/music"]).unwrap();
+        let mut config = overrides.apply(&file).unwrap();
+        // Changed in UI
+        config.concurrent_downloads = 5;
+        config.filename_template = "%title%".to_string();
+        let saved = overrides.unapply(&config, &file).unwrap();
+        assert_eq!(saved.concurrent_downloads, 5);
+        assert_eq!(saved.filename_template, "%title%");
+        assert_eq!(saved.quality, file.quality);
+        assert_eq!(saved.path, file.path);
+    }
+
+    #[test]
+    fn describe_layers() {
+        let vars = [("SPOTIDOWN_QUALITY", "Q96"), ("SPOTIDOWN_PASSWORD", "hunter2")];
+        let overrides = parse(&vars, &["--concurrent-downloads", "3", "--username", "user"]).unwrap();
+        let file_config = json!({ "path": "/music" });
+        let file_account = json!({ "username": "saved", "client_id": "client" });
+        let out = overrides.describe(&DownloaderConfig::fallback(), Some(&file_config), Some(&file_account)).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert!(lines.contains(&"concurrent_downloads = 3 (command line)"));
+        assert!(lines.contains(&"quality = \"Q96\" (environment)"));
+        assert!(lines.iter().any(|l| l.starts_with("path = ") && l.ends_with("(settings file)")));
+        assert!(lines.contains(&"id3v24 = true (default)"));
+        assert!(lines.contains(&"username = user (command line)"));
+        assert!(lines.contains(&"password = <hidden> (environment)"));
+        assert!(lines.contains(&"client_id = \"client\" (settings file)"));
+        assert!(lines.contains(&"client_secret = <stored> (settings file)"));
+        assert!(lines.contains(&"passphrase = <unset> (default)"));
+        assert!(!out.contains("hunter2"));
+    }
+}
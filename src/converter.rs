@@ -47,8 +47,6 @@ impl AudioConverter {
         };
 
         match format {
-            AudioFormat::AAC => todo!(),
-            AudioFormat::MP4 => todo!(),
             // Lewton decoder
             AudioFormat::OGG => {
                 let decoder = OggStreamReader::new(ReadWrap::new(Box::new(read)))?;
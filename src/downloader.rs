@@ -1,25 +1,36 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::path::{Path, PathBuf};
 use std::io::Read;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use chrono::NaiveDate;
 use tokio::io::AsyncWriteExt;
 use tokio::fs::File;
 use futures::stream::FuturesUnordered;
-use librespot::audio::{AudioDecrypt, AudioFile};
-use librespot::core::audio_key::AudioKey;
-use librespot::core::session::Session;
-use librespot::core::spotify_id::SpotifyId;
-use librespot::metadata::{Metadata, Track, FileFormat};
+use futures::future::{Fuse, FusedFuture};
+use librespot::metadata::FileFormat;
 use futures::{FutureExt, Stream, StreamExt, pin_mut, select};
 use async_std::channel::{bounded, Receiver, Sender};
+use tokio::sync::broadcast;
 use sanitize_filename::sanitize;
 use async_stream::{try_stream};
 use serde::{Deserialize, Serialize};
 
+use crate::cache::Cache;
 use crate::converter::AudioConverter;
 use crate::error::SpotifyError;
+use crate::history::History;
+use crate::logger;
+use crate::pool::AccountPool;
 use crate::tag::{Field, TagWrap};
-use crate::spotify::{Spotify, SpotifyItem};
+use crate::spotify::{AudioProvider, MetadataProvider, Spotify, SpotifyItem};
 
+/// Max times job is requeued after session errors
+const MAX_REQUEUE: usize = 3;
+/// Receive timeout while no stats are pending
+const STATS_IDLE_WAIT: Duration = Duration::from_secs(60);
 
 /// Wrapper for use with UI
 #[derive(Debug, Clone)]
@@ -27,27 +38,67 @@ pub struct Downloader {
     rx: Receiver<Response>,
     tx: Sender<Message>,
 
-    spotify: Spotify,
+    metadata: Arc<dyn MetadataProvider>,
+    cache: Cache,
+    pool: AccountPool,
+    events: broadcast::Sender<DownloaderEvent>,
 }
 impl Downloader {
     /// Create new instance
     pub fn new(config: DownloaderConfig, spotify: Spotify) -> Downloader {
+        let name = spotify.credentials.username.to_string();
+        Downloader::with_providers(config, Arc::new(spotify.metadata.clone()), &name, Arc::new(spotify), Cache::new(), History::load())
+    }
+
+    /// Create new instance with custom metadata and audio sources, cache and history
+    pub fn with_providers(
+        config: DownloaderConfig,
+        metadata: Arc<dyn MetadataProvider>,
+        name: &str,
+        audio: Arc<dyn AudioProvider>,
+        cache: Cache,
+        history: History
+    ) -> Downloader {
         let (tx_0, rx_0) = bounded(1);
         let (tx_1, rx_1) = bounded(1);
 
         let tx_clone = tx_1.clone();
-        let spotify_clone = spotify.clone();
+        let metadata_clone = metadata.clone();
+        let cache_clone = cache.clone();
+        let (events, _) = broadcast::channel(1024);
+        let events_clone = events.clone();
+        let pool = AccountPool::new();
+        pool.add(name, audio, config.concurrent_downloads);
+        let pool_clone = pool.clone();
         tokio::spawn(async move {
-            communication_thread(config, spotify_clone, rx_1, tx_0, tx_clone).await
+            communication_thread(config, metadata_clone, pool_clone, cache_clone, history, rx_1, tx_0, tx_clone, events_clone).await
         });
         
         Downloader {
             rx: rx_0,
             tx: tx_1,
-            spotify,
+            metadata,
+            cache,
+            pool,
+            events,
         }
     }
 
+    /// Add account to download pool
+    pub fn add_account(&self, name: &str, audio: Arc<dyn AudioProvider>, limit: usize) {
+        self.pool.add(name, audio, limit);
+    }
+
+    /// Change concurrency limit of account in download pool
+    pub fn set_account_limit(&self, name: &str, limit: usize) {
+        self.pool.set_limit(name, limit);
+    }
+
+    /// Subscribe to queue events
+    pub fn subscribe(&self) -> broadcast::Receiver<DownloaderEvent> {
+        self.events.subscribe()
+    }
+
     /// Set new downloader config
     pub async fn set_config(&mut self, config: DownloaderConfig) {
         self.tx.send(Message::SetConfig(config)).await.unwrap();
@@ -65,18 +116,39 @@ impl Downloader {
 
     /// Add URL or URI to queue
     pub async fn add_uri(&self, uri: &str) -> Result<(), SpotifyError> {
+        self.add_uri_with(uri, None).await
+    }
+
+    /// Add URL or URI to queue, overrides are merged over config of each track
+    pub async fn add_uri_with(&self, uri: &str, overrides: Option<DownloadOverrides>) -> Result<(), SpotifyError> {
         let uri = Spotify::parse_uri(uri)?;
-        let item = self.spotify.resolve_uri(&uri).await?;
+        let item = self.metadata.resolve_uri(&uri).await?;
+        let priority = overrides.as_ref().and_then(|o| o.priority).unwrap_or(0);
+        let with_overrides = |mut d: Download| {
+            d.overrides = overrides.clone();
+            d.priority = priority;
+            d
+        };
         match item {
-            SpotifyItem::Track(t) => self.add_to_queue(t.into()).await,
+            SpotifyItem::Track(t) => self.add_to_queue(with_overrides(t.into())).await,
             SpotifyItem::Album(a) => {
-                let tracks = self.spotify.full_album(&a.id).await?;
-                let queue: Vec<Download> = tracks.into_iter().map(|t| t.into()).collect();
+                let tracks = self.metadata.full_album(&a.id).await?;
+                self.cache.insert_album(a);
+                // Prefetch full tracks, workers will fetch them on failure
+                let ids: Vec<&str> = tracks.iter().filter_map(|t| t.id.as_deref()).collect();
+                let queue: Vec<Download> = match self.metadata.get_tracks(&ids).await {
+                    Ok(full) => full.into_iter().map(|t| with_overrides(t.into())).collect(),
+                    Err(e) => {
+                        warn!("Failed prefetching album tracks: {}", e);
+                        tracks.into_iter().map(|t| with_overrides(t.into())).collect()
+                    }
+                };
                 self.add_to_queue_multiple(queue).await;
             },
             SpotifyItem::Playlist(p) => {
-                let tracks = self.spotify.full_playlist(&p.id).await?;
-                let queue: Vec<Download> = tracks.into_iter().map(|t| t.into()).collect();
+                let tracks = self.metadata.full_playlist(&p.id).await?;
+                self.prefetch_albums(&tracks).await;
+                let queue: Vec<Download> = tracks.into_iter().map(|t| with_overrides(t.into())).collect();
                 self.add_to_queue_multiple(queue).await;
             }
             // Unsupported
@@ -89,38 +161,123 @@ impl Downloader {
         Ok(())
     }
 
+    /// Fetch albums of tracks in batches into cache
+    async fn prefetch_albums(&self, tracks: &[aspotify::Track]) {
+        let mut ids: Vec<&str> = tracks.iter().filter_map(|t| t.album.id.as_deref()).collect();
+        ids.sort();
+        ids.dedup();
+        match self.metadata.get_albums(&ids).await {
+            Ok(albums) => albums.into_iter().for_each(|a| self.cache.insert_album(a)),
+            Err(e) => warn!("Failed prefetching albums: {}", e)
+        }
+    }
+
     /// Get all downloads
     pub async fn get_downloads(&self) -> Vec<Download> {
         self.tx.send(Message::GetDownloads).await.unwrap();
         let Response::Downloads(d) = self.rx.recv().await.unwrap();
         d
     }
+
+    /// Move pending download in queue
+    pub async fn move_download(&self, id: i64, direction: QueueMove) {
+        self.tx.send(Message::Move(id, direction)).await.ok();
+    }
+
+    /// Stop workers and close sessions of all accounts, running downloads are cancelled
+    pub async fn shutdown(&self) {
+        self.tx.send(Message::Shutdown).await.ok();
+        self.pool.shutdown();
+    }
+
+    /// Write JSON report of failed downloads
+    pub async fn export_errors(&self, path: impl AsRef<Path>) -> Result<(), SpotifyError> {
+        let report: Vec<JobErrorReport> = self.get_downloads().await.iter().filter_map(|d| match &d.state {
+            DownloadState::Error(e) => Some(JobErrorReport::new(d, e)),
+            _ => None
+        }).collect();
+        tokio::fs::write(path, serde_json::to_vec_pretty(&report)?).await?;
+        Ok(())
+    }
 }
 
 async fn communication_thread(
     mut config: DownloaderConfig, 
-    spotify: Spotify,
+    metadata: Arc<dyn MetadataProvider>,
+    pool: AccountPool,
+    cache: Cache,
+    history: History,
     rx: Receiver<Message>,
     tx: Sender<Response>,
     self_tx: Sender<Message>,
+    events: broadcast::Sender<DownloaderEvent>,
 ) {
     // Downloader
-    let downloader = DownloaderInternal::new(spotify.clone(), self_tx.clone());
+    let downloader = DownloaderInternal::new(metadata, pool, cache.clone(), history.clone(), self_tx.clone());
     let downloader_tx = downloader.tx.clone();
     tokio::spawn(async move {
         downloader.download_loop().await;
     });
     let mut waiting_for_job = false;
     let mut queue: Vec<Download> = vec![];
+    // IDs aren't reused, events refer to them
+    let mut next_id: i64 = 0;
+    // Session statistics
+    let session_start = Instant::now();
+    let mut session_done = 0;
+    let mut session_downgraded = 0;
+    let mut session_duplicates = 0;
+    let mut session_bytes = 0;
+    let mut progress: HashMap<i64, usize> = HashMap::new();
+    let mut last_stats: Option<QueueStats> = None;
+    // Stats are sent at most once per progress interval
+    let mut stats_dirty = false;
+    let mut stats_sent: Option<Instant> = None;
+    let mut requeue_count: HashMap<i64, usize> = HashMap::new();
 
-    // Receive messages
-    while let Ok(msg) = rx.recv().await {
+    loop {
+        let interval = Duration::from_millis(config.progress_interval);
+        if stats_dirty && stats_sent.map(|t| t.elapsed() >= interval).unwrap_or(true) {
+            let mut stats = queue_stats(&queue, session_done, session_bytes, session_start);
+            stats.downgraded = session_downgraded;
+            stats.duplicates = session_duplicates;
+            if stats.pending == 0 && stats.downloading == 0 && last_stats.as_ref().map(|s| s.pending + s.downloading > 0).unwrap_or(false) {
+                info!("Queue finished: {} done, {} failed, {} downloaded, average {}/s",
+                    stats.done, stats.failed, stats.bytes, stats.average_speed);
+            }
+            events.send(DownloaderEvent::Stats(stats.clone())).ok();
+            last_stats = Some(stats);
+            stats_sent = Some(Instant::now());
+            stats_dirty = false;
+        }
+        // Receive messages, wake up to send pending stats
+        let wait = match (stats_dirty, stats_sent) {
+            (true, Some(sent)) => interval.saturating_sub(sent.elapsed()),
+            _ => STATS_IDLE_WAIT
+        };
+        let mut msg = match tokio::time::timeout(wait, rx.recv()).await {
+            Ok(Ok(msg)) => msg,
+            Ok(Err(_)) => break,
+            Err(_) => continue
+        };
+        // Back to queue, failed after too many attempts
+        if let Message::Requeue(id, error) = msg {
+            let count = requeue_count.entry(id).or_insert(0);
+            *count += 1;
+            msg = match *count > MAX_REQUEUE {
+                true => Message::UpdateState(id, DownloadState::Error(error)),
+                false => {
+                    info!("Requeued job {} after session error ({}/{})", id, count, MAX_REQUEUE);
+                    Message::UpdateState(id, DownloadState::None)
+                }
+            };
+        }
         match msg {
             // Send job to worker thread
             Message::GetJob => {
                 if let Some(d) = queue.iter_mut().find(|i| i.state == DownloadState::None) {
                     d.state = DownloadState::Lock;
-                    downloader_tx.send(DownloaderMessage::Job(d.clone().into(), config.clone())).await.unwrap();
+                    downloader_tx.send(DownloaderMessage::Job(d.clone().into(), d.config(&config))).await.unwrap();
                     waiting_for_job = false;
                 } else {
                     waiting_for_job = true;
@@ -130,27 +287,109 @@ async fn communication_thread(
             Message::UpdateState(id, state) => {
                 let i = queue.iter().position(|i| i.id == id).unwrap();
                 queue[i].state = state.clone();
-                if state == DownloadState::Done {
-                    queue.remove(i);
+                let requeued = state == DownloadState::None;
+                // Count downloaded bytes
+                if let DownloadState::Downloading(r, _, _) = state {
+                    let last = progress.insert(id, r).unwrap_or(0);
+                    session_bytes += r.saturating_sub(last);
+                }
+                match state {
+                    DownloadState::Done => {
+                        session_done += 1;
+                        progress.remove(&id);
+                    }
+                    DownloadState::Error(_) | DownloadState::None => { progress.remove(&id); }
+                    _ => {}
+                }
+
+                // Send errors are fine, means no subscribers
+                match state {
+                    DownloadState::Downloading(r, t, s) => events.send(DownloaderEvent::Progress(id, r, t, s)).ok(),
+                    DownloadState::Done => {
+                        queue.remove(i);
+                        events.send(DownloaderEvent::Removed(id)).ok()
+                    },
+                    state => events.send(DownloaderEvent::StateChanged(id, state)).ok()
+                };
+
+                stats_dirty = true;
+
+                // Requeued job, update worker threads if locked
+                if waiting_for_job && requeued {
+                    queue[i].state = DownloadState::Lock;
+                    downloader_tx.send(DownloaderMessage::Job(queue[i].clone().into(), queue[i].config(&config))).await.unwrap();
+                    waiting_for_job = false;
                 }
             }
             Message::AddToQueue(download) => {
-                // Assign new IDs and reset state
-                let mut id = queue.iter().map(|i| i.id).max().unwrap_or(0);
-                let downloads: Vec<Download> = download.into_iter().map(|mut d| {
-                    d.id = id;
+                // Skip output paths already queued or downloaded and tracks downloaded before, failed ones can be added again.
+                // Without metadata the path isn't known, then same track with same overrides is a duplicate
+                let queued = queue.iter().filter(|d| !matches!(d.state, DownloadState::Error(_)));
+                let mut paths: HashSet<PathBuf> = queued.clone().filter_map(|d| d.path.clone()).collect();
+                let mut tracks: HashSet<(String, Option<DownloadOverrides>)> = queued
+                    .filter(|d| d.path.is_none())
+                    .map(|d| (d.track_id.to_string(), d.overrides.clone()))
+                    .collect();
+                let mut duplicates = 0;
+                let mut downloads = vec![];
+                for mut d in download {
+                    d.path = resolve_path(&d, &d.config(&config), &cache);
+                    let duplicate = history.contains_track(&d.track_id) || match &d.path {
+                        Some(path) => !paths.insert(path.clone()) || history.contains_path(path),
+                        None => !tracks.insert((d.track_id.to_string(), d.overrides.clone()))
+                    };
+                    if duplicate {
+                        duplicates += 1;
+                        continue;
+                    }
+                    // Assign new ID and reset state
+                    d.id = next_id;
                     d.state = DownloadState::None;
-                    id += 1;
-                    d
-                }).collect();
-                queue.extend(downloads);
-                
+                    next_id += 1;
+                    downloads.push(d);
+                }
+                if duplicates > 0 {
+                    info!("Skipped {} duplicate downloads", duplicates);
+                    session_duplicates += duplicates;
+                }
+
+                // Replace failed downloads of same tracks
+                let added: HashSet<&str> = downloads.iter().map(|d| d.track_id.as_str()).collect();
+                queue.retain(|d| match d.state {
+                    DownloadState::Error(_) if added.contains(d.track_id.as_str()) => {
+                        events.send(DownloaderEvent::Removed(d.id)).ok();
+                        false
+                    }
+                    _ => true
+                });
+                if !downloads.is_empty() {
+                    events.send(DownloaderEvent::Added(downloads.clone())).ok();
+                    queue.extend(downloads);
+                }
+                // Keep queue sorted by priority
+                if queue.windows(2).any(|w| w[0].priority < w[1].priority) {
+                    queue.sort_by_key(|d| Reverse(d.priority));
+                    events.send(DownloaderEvent::Reordered(queue_order(&queue))).ok();
+                }
+                stats_dirty = true;
+
                 // Update worker threads if locked
                 if waiting_for_job {
-                    let d = queue.iter_mut().find(|i| i.state == DownloadState::None).unwrap();
-                    d.state = DownloadState::Lock;
-                    downloader_tx.send(DownloaderMessage::Job(d.clone().into(), config.clone())).await.unwrap();
-                    waiting_for_job = false;
+                    if let Some(d) = queue.iter_mut().find(|i| i.state == DownloadState::None) {
+                        d.state = DownloadState::Lock;
+                        downloader_tx.send(DownloaderMessage::Job(d.clone().into(), d.config(&config))).await.unwrap();
+                        waiting_for_job = false;
+                    }
+                }
+            }
+            // Output path taken when job started
+            Message::Duplicate(id) => {
+                if let Some(i) = queue.iter().position(|d| d.id == id) {
+                    queue.remove(i);
+                    progress.remove(&id);
+                    session_duplicates += 1;
+                    events.send(DownloaderEvent::Removed(id)).ok();
+                    stats_dirty = true;
                 }
             }
             Message::GetDownloads => {
@@ -159,13 +398,92 @@ async fn communication_thread(
             Message::SetConfig(c) => {
                 config = c;
             }
+            Message::Move(id, direction) => {
+                if let Some(i) = queue.iter().position(|d| d.id == id && d.state == DownloadState::None) {
+                    move_download(&mut queue, i, direction);
+                    events.send(DownloaderEvent::Reordered(queue_order(&queue))).ok();
+                }
+            }
+            Message::Downgraded(id, quality) => {
+                session_downgraded += 1;
+                stats_dirty = true;
+                if let Some(d) = queue.iter_mut().find(|d| d.id == id) {
+                    d.quality = Some(quality);
+                }
+                events.send(DownloaderEvent::Downgraded(id, quality)).ok();
+            }
+            // Converted to UpdateState above
+            Message::Requeue(_, _) => {}
+            Message::Shutdown => {
+                // Worker loop exits once it can't get more jobs
+                downloader_tx.close();
+                break;
+            }
+        }
+    }
+}
+
+/// Move pending download, takes priority of new neighbour so queue stays sorted, top goes above all
+fn move_download(queue: &mut Vec<Download>, i: usize, direction: QueueMove) {
+    let pending = |d: &Download| d.state == DownloadState::None;
+    let target = match direction {
+        QueueMove::Top => Some(0),
+        QueueMove::Up => queue[..i].iter().rposition(pending),
+        QueueMove::Down => queue[i + 1..].iter().position(pending).map(|j| i + 1 + j)
+    };
+    if let Some(j) = target {
+        let priority = match direction {
+            QueueMove::Top => queue.iter().map(|d| d.priority).max().unwrap_or(0).saturating_add(1),
+            _ => queue[j].priority
+        };
+        let mut d = queue.remove(i);
+        d.priority = priority;
+        queue.insert(j, d);
+    }
+}
+
+/// Output path from prefetched metadata, None if track or album isn't available yet
+fn resolve_path(download: &Download, config: &DownloaderConfig, cache: &Cache) -> Option<PathBuf> {
+    let track = download.track.as_ref()?;
+    let album = cache.get_album(track.album.id.as_ref()?)?;
+    let values = DownloaderInternal::template_values(&download.track_id, track, &album);
+    Some(DownloaderInternal::output_path(config, &values))
+}
+
+/// IDs and priorities in queue order
+fn queue_order(queue: &[Download]) -> Vec<(i64, i32)> {
+    queue.iter().map(|d| (d.id, d.priority)).collect()
+}
+
+/// Calculate queue statistics
+fn queue_stats(queue: &[Download], done: usize, bytes: usize, start: Instant) -> QueueStats {
+    let mut stats = QueueStats {
+        done,
+        bytes,
+        average_speed: (bytes as f64 / start.elapsed().as_secs_f64().max(1.0)) as usize,
+        ..Default::default()
+    };
+    for d in queue {
+        match d.state {
+            DownloadState::None | DownloadState::Lock => stats.pending += 1,
+            DownloadState::Downloading(_, _, s) => {
+                stats.downloading += 1;
+                stats.speed += s;
+            },
+            DownloadState::Post => stats.downloading += 1,
+            DownloadState::Error(_) => stats.failed += 1,
+            DownloadState::Done => {}
         }
     }
+    stats
 }
 
 /// Spotify downloader
 pub struct DownloaderInternal {
-    spotify: Spotify,
+    metadata: Arc<dyn MetadataProvider>,
+    pool: AccountPool,
+    cache: Cache,
+    history: History,
     pub tx: Sender<DownloaderMessage>,
     rx: Receiver<DownloaderMessage>,
     event_tx: Sender<Message>
@@ -177,48 +495,50 @@ pub enum DownloaderMessage {
 
 impl DownloaderInternal {
     /// Create new instance
-    pub fn new(spotify: Spotify, event_tx: Sender<Message>) -> DownloaderInternal {
+    pub fn new(metadata: Arc<dyn MetadataProvider>, pool: AccountPool, cache: Cache, history: History, event_tx: Sender<Message>) -> DownloaderInternal {
         let (tx, rx) = bounded(1);
         DownloaderInternal {
-            spotify,
+            metadata,
+            pool,
+            cache,
+            history,
             tx,
             rx,
             event_tx
         }
     }
 
-    /// Downloader loop
+    /// Downloader loop, jobs are taken only when account slot is free so queue order and priorities apply until they start
     pub async fn download_loop(&self) {
-        let mut queue = vec![];
         let mut tasks = FuturesUnordered::new();
-        let mut job_future = Box::pin(self.get_job()).fuse();
+        let mut job_future = Fuse::terminated();
 
         loop {
+            // Running jobs are dropped on shutdown
+            if self.pool.is_closed() {
+                break;
+            }
+            // Each account has own limit, accounts connected later raise it
+            let changed = self.pool.changed();
+            if job_future.is_terminated() && tasks.len() < self.pool.capacity() {
+                job_future = Box::pin(self.get_job()).fuse();
+            }
             select! {
-                job = job_future => {
-                    if let Some((job, config)) = job {
-                        if tasks.len() < config.concurrent_downloads {
-                            tasks.push(self.download_job_wrapper(job.clone(), config).boxed())
-                        } else {
-                            queue.push((job, config));
-                        }
-                    }
-                    job_future = Box::pin(self.get_job()).fuse();
+                job = job_future => match job {
+                    Some((job, config)) => tasks.push(self.download_job_wrapper(job, config).boxed()),
+                    // Shut down
+                    None => break
                 },
                 // Task finished
-                () = tasks.select_next_some() => {
-                    if let Some((job, config)) = queue.first() {
-                        tasks.push(self.download_job_wrapper(job.clone(), config.clone()).boxed());
-                        queue.remove(0);
-                    }
-                }
+                () = tasks.select_next_some() => {},
+                () = changed.fuse() => {}
             };
         }
     }
 
     // Get job from parent
     async fn get_job(&self) -> Option<(DownloadJob, DownloaderConfig)> {
-        self.event_tx.send(Message::GetJob).await.unwrap();
+        self.event_tx.send(Message::GetJob).await.ok()?;
         match self.rx.recv().await.ok()? {
             DownloaderMessage::Job(job, config) => Some((job, config))
         }
@@ -226,52 +546,81 @@ impl DownloaderInternal {
 
     /// Wrapper for download_job for error handling
     async fn download_job_wrapper(&self, job: DownloadJob, config: DownloaderConfig) {
+        // Pause while rate limited
+        self.metadata.rate_limit().wait().await;
         let track_id = job.track_id.clone();
         let id = job.id;
-        match self.download_job(job, config).await {
-            Ok(_) => {},
-            Err(e) => {
-                error!("Download job for track {} failed. {}", track_id, e);
-                self.event_tx.send(Message::UpdateState(id, DownloadState::Error(e.to_string()))).await.unwrap();
+        logger::with_job(id, &track_id, async {
+            match self.download_job(job, config).await {
+                Ok(_) => {},
+                // Session dropped, retry once reconnected
+                Err(e) if e.error.is_session() => {
+                    warn!("Download job interrupted, requeueing. {}", e);
+                    self.event_tx.send(Message::Requeue(id, e)).await.ok();
+                }
+                Err(e) if e.error == SpotifyError::Duplicate => {
+                    info!("Skipping duplicate download job. {}", e);
+                    self.event_tx.send(Message::Duplicate(id)).await.ok();
+                }
+                Err(e) => {
+                    error!("Download job failed. {}", e);
+                    self.event_tx.send(Message::UpdateState(id, DownloadState::Error(e))).await.ok();
+                }
             }
-        }
+        }).await;
     }
 
     // Wrapper for downloading and tagging
-    async fn download_job(&self, job: DownloadJob, config: DownloaderConfig) -> Result<(), SpotifyError> {
+    async fn download_job(&self, job: DownloadJob, config: DownloaderConfig) -> Result<(), JobError> {
         // Fetch metadata
-        let track = self.spotify.spotify.tracks().get_track(&job.track_id, None).await?.data;
-        let album = self.spotify.spotify.albums().get_album(&track.album.id.ok_or(SpotifyError::Unavailable)?, None).await?.data;
+        let (track, album, disc_total) = async {
+            let track = match job.track.as_ref() {
+                Some(track) => (**track).clone(),
+                None => self.metadata.get_track(&job.track_id).await?
+            };
+            let album_id = track.album.id.as_ref().ok_or(SpotifyError::Unavailable)?;
+            let album = self.cache.album(album_id, config.cache_on_disk, || self.metadata.get_album(album_id)).await?;
+            // Disc count from album tracklist, first page might not contain all tracks
+            let disc_total = self.cache.disc_total(&album.id, || async {
+                let disc_total = album.tracks.items.iter().map(|t| t.disc_number).max().unwrap_or(1);
+                if album.tracks.items.len() < album.tracks.total {
+                    return Ok(self.metadata.full_album(&album.id).await?.iter().map(|t| t.disc_number).max().unwrap_or(disc_total));
+                }
+                Ok(disc_total)
+            }).await?;
+            Ok::<_, SpotifyError>((track, album, disc_total))
+        }.await.stage(JobStage::Metadata, &job.track_id)?;
         // Generate path
-        let mut filename = config.filename_template.to_owned();
-        let tags: Vec<(&str, String)> = vec![
-            ("%title%", sanitize(&track.name)),
-            ("%artist%", sanitize(&track.artists.iter().map(|a| a.name.as_str()).collect::<Vec<&str>>().first().unwrap_or(&""))),
-            ("%artists%", sanitize(&track.artists.iter().map(|a| a.name.as_str()).collect::<Vec<&str>>().join(", "))),
-            ("%track%", track.track_number.to_string()),
-            ("%0track%", format!("{:02}", track.track_number)),
-            ("%disc%", track.disc_number.to_string()),
-            ("%0disc%", format!("{:02}", track.disc_number)),
-            ("%id%", job.track_id.to_string()),
-            ("%album%", sanitize(&track.album.name)),
-            ("%albumArtist%", sanitize(&track.album.artists.iter().map(|a| a.name.as_str()).collect::<Vec<&str>>().first().unwrap_or(&""))),
-            ("%albumArtists%", sanitize(&track.album.artists.iter().map(|a| a.name.as_str()).collect::<Vec<&str>>().join(", ")))
-        ];
-        for (t, v) in tags {
-            filename = filename.replace(t, &v);
+        let values = DownloaderInternal::template_values(&job.track_id, &track, &album);
+        let path = DownloaderInternal::output_path(&config, &values);
+        // Same file or track downloaded before, or being written by another job
+        if self.history.contains_path(&path) || self.history.contains_track(&job.track_id) {
+            return Err(JobError::new(JobStage::Download, &job.track_id, SpotifyError::Duplicate));
         }
-        let path = config.path.join(filename);
-        tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+        let _claim = self.history.claim(&path)
+            .ok_or(SpotifyError::Duplicate)
+            .stage(JobStage::Download, &job.track_id)?;
+        tokio::fs::create_dir_all(path.parent().unwrap()).await.stage(JobStage::Download, &job.track_id)?;
 
-        // Download
-        let (path, format) = DownloaderInternal::download_track(
-            &self.spotify.session, 
+        // Download using free account
+        let lease = self.pool.acquire().await.stage(JobStage::Download, &job.track_id)?;
+        let result = DownloaderInternal::download_track(
+            lease.audio(), 
             &job.track_id, 
             path, 
             config.clone(),
             self.event_tx.clone(),
-        job.id
-        ).await?;
+            job.id
+        ).await;
+        if let Err(e) = &result {
+            lease.report(&e.error);
+        }
+        drop(lease);
+        let (path, format, quality) = result?;
+        if quality != config.quality {
+            warn!("Got {} instead of requested {}", quality.to_string(), config.quality.to_string());
+            self.event_tx.send(Message::Downgraded(job.id, quality)).await.ok();
+        }
         
         // Post processing
         self.event_tx.send(Message::UpdateState(job.id, DownloadState::Post)).await.ok();
@@ -279,7 +628,9 @@ impl DownloaderInternal {
         // Download cover
         let mut cover = None;
         if let Some(image) = track.album.images.first() {
-            match DownloaderInternal::download_cover(&image.url).await {
+            let url = image.url.to_string();
+            let client = self.metadata.http_client();
+            match self.cache.cover(&url, config.cache_on_disk, || DownloaderInternal::download_cover(&client, &url)).await {
                 Ok(c) => cover = Some(c),
                 Err(e) => warn!("Failed downloading cover! {}", e)
             }
@@ -293,23 +644,46 @@ impl DownloaderInternal {
             (Field::TrackNumber, vec![track.track_number.to_string()]),
             (Field::DiscNumber, vec![track.disc_number.to_string()]),
             (Field::Genre, album.genres.clone()),
-            (Field::Label, vec![album.label.to_string()])
+            (Field::Label, vec![album.label.to_string()]),
+            (Field::TrackTotal, vec![album.tracks.total.to_string()]),
+            (Field::DiscTotal, vec![disc_total.to_string()]),
+            (Field::Explicit, vec![(track.explicit as u8).to_string()]),
+            (Field::Copyright, album.copyrights.iter().map(|c| c.text.to_string()).collect::<Vec<String>>()),
+            (Field::Popularity, vec![track.popularity.to_string()]),
+            (Field::TrackId, vec![job.track_id.to_string()]),
+            (Field::AlbumId, vec![album.id.to_string()]),
+            (Field::ArtistId, track.artists.iter().filter_map(|a| a.id.clone()).collect::<Vec<String>>()),
+        ];
+        // Optional
+        let mut tags = tags;
+        let optional = vec![
+            (Field::ISRC, track.external_ids.get("isrc")),
+            (Field::UPC, album.external_ids.get("upc")),
+            (Field::URL, track.external_urls.get("spotify")),
+            (Field::AlbumURL, album.external_urls.get("spotify")),
         ];
+        for (field, value) in optional {
+            if let Some(value) = value {
+                tags.push((field, vec![value.to_string()]));
+            }
+        }
         let date = album.release_date;
         // Write tags
         let config = config.clone();
-        tokio::task::spawn_blocking(move || {
-            DownloaderInternal::write_tags(path, format, tags, date, cover, config)
-        }).await??;
+        let path_clone = path.clone();
+        logger::spawn_blocking(move || {
+            DownloaderInternal::write_tags(path_clone, format, tags, date, cover, values, config)
+        }).await.stage(JobStage::Tag, &job.track_id)?.stage(JobStage::Tag, &job.track_id)?;
 
         // Done
+        self.history.add(&job.track_id, &path, quality).await;
         self.event_tx.send(Message::UpdateState(job.id, DownloadState::Done)).await.ok();
         Ok(())
     }
 
     /// Download cover, returns mime and data
-    async fn download_cover(url: &str) -> Result<(String, Vec<u8>), SpotifyError> {
-        let res = reqwest::get(url).await?;
+    async fn download_cover(client: &reqwest::Client, url: &str) -> Result<(String, Vec<u8>), SpotifyError> {
+        let res = client.get(url).send().await?;
         let mime = res.headers().get("content-type")
             .ok_or(SpotifyError::Error("Missing cover mime!".into()))?
             .to_str().unwrap().to_string();
@@ -317,6 +691,43 @@ impl DownloaderInternal {
         Ok((mime, data))
     }
 
+    /// Get template variables and their (unsanitized) values
+    fn template_values(id: &str, track: &aspotify::Track, album: &aspotify::Album) -> Vec<(&'static str, String)> {
+        vec![
+            ("%title%", track.name.to_string()),
+            ("%artist%", track.artists.iter().map(|a| a.name.as_str()).collect::<Vec<&str>>().first().unwrap_or(&"").to_string()),
+            ("%artists%", track.artists.iter().map(|a| a.name.as_str()).collect::<Vec<&str>>().join(", ")),
+            ("%track%", track.track_number.to_string()),
+            ("%0track%", format!("{:02}", track.track_number)),
+            ("%disc%", track.disc_number.to_string()),
+            ("%0disc%", format!("{:02}", track.disc_number)),
+            ("%id%", id.to_string()),
+            ("%album%", track.album.name.to_string()),
+            ("%albumArtist%", track.album.artists.iter().map(|a| a.name.as_str()).collect::<Vec<&str>>().first().unwrap_or(&"").to_string()),
+            ("%albumArtists%", track.album.artists.iter().map(|a| a.name.as_str()).collect::<Vec<&str>>().join(", ")),
+            ("%albumId%", album.id.to_string()),
+            ("%url%", track.external_urls.get("spotify").map(|u| u.to_string()).unwrap_or(String::new())),
+            ("%albumUrl%", album.external_urls.get("spotify").map(|u| u.to_string()).unwrap_or(String::new())),
+            ("%isrc%", track.external_ids.get("isrc").map(|u| u.to_string()).unwrap_or(String::new())),
+            ("%label%", album.label.to_string()),
+        ]
+    }
+
+    /// Output path without extension
+    fn output_path(config: &DownloaderConfig, values: &[(&str, String)]) -> PathBuf {
+        let sanitized: Vec<(&str, String)> = values.iter().map(|(t, v)| (*t, sanitize(v))).collect();
+        config.path.join(DownloaderInternal::fill_template(&config.filename_template, &sanitized))
+    }
+
+    /// Replace template variables
+    fn fill_template(template: &str, values: &[(&str, String)]) -> String {
+        let mut out = template.to_owned();
+        for (t, v) in values {
+            out = out.replace(t, v);
+        }
+        out
+    }
+
     /// Write tags to file ( BLOCKING )
     fn write_tags(
         path: impl AsRef<Path>, 
@@ -324,24 +735,48 @@ impl DownloaderInternal {
         tags: Vec<(Field, Vec<String>)>, 
         date: NaiveDate,
         cover: Option<(String, Vec<u8>)>,
+        values: Vec<(&str, String)>,
         config: DownloaderConfig
     ) -> Result<(), SpotifyError> {
-        let mut tag_wrap = TagWrap::new(path, format)?;
+        let mut tag_wrap = TagWrap::new(path, format.clone())?;
         // Format specific
         match &mut tag_wrap {
             TagWrap::ID3(id3) => id3.use_id3_v24(config.id3v24),
             _ => {}
         }
 
+        // Custom mappings for this format
+        let custom = match format {
+            AudioFormat::MP3 => config.custom_tags.id3.clone(),
+            AudioFormat::AAC | AudioFormat::MP4 => config.custom_tags.mp4.clone(),
+            AudioFormat::OGG => config.custom_tags.ogg.clone(),
+            AudioFormat::Unknown => vec![]
+        };
+
         let tag = tag_wrap.get_tag();
         tag.set_separator(&config.separator);
+        if config.tag_policy == TagPolicy::Replace {
+            tag.clear();
+        }
+        let merge = config.tag_policy == TagPolicy::Merge;
         for (field, value) in tags {
+            if config.custom_tags.disabled.contains(&field) || (merge && tag.has_field(&field)) {
+                continue;
+            }
             tag.set_field(field, value);
         }
-        tag.set_release_date(date);
+        if !(merge && tag.has_release_date()) {
+            tag.set_release_date(date);
+        }
         // Cover
         if let Some((mime, data)) = cover {
-            tag.add_cover(&mime, data);
+            if !(merge && tag.has_cover()) {
+                tag.add_cover(&mime, data);
+            }
+        }
+        // Custom tags, written after built-in fields
+        for (name, template) in custom {
+            tag.set_raw(&name, vec![DownloaderInternal::fill_template(&template, &values)]);
         }
         tag.save()?;
         Ok(())
@@ -349,36 +784,27 @@ impl DownloaderInternal {
 
     /// Download track by id
     async fn download_track(
-        session: &Session, 
+        audio: &dyn AudioProvider, 
         id: &str, 
         path: impl AsRef<Path>, 
         config: DownloaderConfig, 
         tx: Sender<Message>, 
         job_id: i64
-    ) -> Result<(PathBuf, AudioFormat), SpotifyError> {
-        let id = SpotifyId::from_base62(id)?;
-        let mut track = Track::get(&session, id).await?;
-    
-        // Fallback if unavailable
-        if !track.available {
-            for alt in track.alternatives {
-                let t = Track::get(&session, alt).await?;
-                if t.available {
-                    track = t;
-                    break;
-                }
-            }
-            return Err(SpotifyError::Unavailable);
-        }
+    ) -> Result<(PathBuf, AudioFormat, Quality), JobError> {
+        let track = audio.audio_files(id).await.stage(JobStage::Metadata, id)?;
         
         // Quality fallback
-        let mut quality = config.quality.clone();
-        let (mut file_id, mut file_format) = (None, None);
+        // Account might not have access to requested quality
+        let account_type = audio.account_type();
+        let mut quality = config.quality.cap(account_type.max_quality());
+        if quality != config.quality {
+            info!("{} Quality capped to {} for {} account", id, quality.to_string(), account_type.to_string());
+        }
+        let mut file_format = None;
         'outer: loop {
             for format in quality.get_file_formats() {
-                if let Some(f) = track.files.get(&format) {
-                    info!("{} Using {:?} format.", id.to_base62(), format);
-                    file_id = Some(f);
+                if track.files.contains_key(&format) {
+                    info!("{} Using {:?} format.", track.id, format);
                     file_format = Some(format);
                     break 'outer;
                 }
@@ -388,69 +814,74 @@ impl DownloaderInternal {
                 Some(q) => quality = q,
                 None => break
             }
-            warn!("{} Falling back to: {:?}", id.to_base62(), quality);
+            warn!("{} Falling back to: {:?}", track.id, quality);
         }
-        let file_id = file_id.ok_or(SpotifyError::Unavailable)?;
-        let file_format = file_format.unwrap();
+        let file_format = file_format.ok_or(SpotifyError::Unavailable).stage(JobStage::Metadata, id)?;
 
         // Path with extension
         let mut audio_format: AudioFormat = file_format.into();
-        let path = format!("{}.{}", path.as_ref().to_str().unwrap(), match config.convert_to_mp3 {
+        // Only Ogg Vorbis can be converted, other formats are saved as they are
+        let convert = config.convert_to_mp3 && matches!(audio_format, AudioFormat::OGG);
+        if config.convert_to_mp3 && !convert {
+            warn!("{} Converting {:?} to MP3 isn't supported, saving without conversion", id, audio_format);
+        }
+        let path = format!("{}.{}", path.as_ref().to_str().unwrap(), match convert {
             true => "mp3".to_string(),
             false => audio_format.extension()
         });
         let path = Path::new(&path).to_owned();
         let path_clone = path.clone();
 
-        let key = session.audio_key().request(track.id, *file_id).await?;
-        let encrypted = AudioFile::open(&session, *file_id, 1024*1024, true).await?;
-        let size = encrypted.get_stream_loader_controller().len();
+        let (decrypted, size) = audio.open_file(&track, file_format).await.stage(JobStage::Key, id)?;
+        let stage = match convert {
+            true => JobStage::Convert,
+            false => JobStage::Download
+        };
         // Download
-        let s = match config.convert_to_mp3 {
+        let s = match convert {
             true => {
-                let s = DownloaderInternal::download_track_convert_stream(path_clone, encrypted, key, audio_format.clone(), quality.clone()).boxed();
+                let s = DownloaderInternal::download_track_convert_stream(path_clone, decrypted, audio_format.clone(), quality.clone()).boxed();
                 audio_format = AudioFormat::MP3;
                 s
             }
-            false => DownloaderInternal::download_track_stream(path_clone, encrypted, key).boxed()
+            false => DownloaderInternal::download_track_stream(path_clone, decrypted).boxed()
         }; 
         pin_mut!(s);
-        // Read progress
+        // Read progress, merged to one update per interval
         let mut read = 0;
+        let interval = Duration::from_millis(config.progress_interval);
+        let mut last_update: Option<Instant> = None;
+        let start = Instant::now();
         while let Some(result) = s.next().await {
             match result {
                 Ok(r) => {
                     read += r;
-                    tx.send(Message::UpdateState(job_id, DownloadState::Downloading(read, size))).await.ok();
+                    if last_update.map(|l| l.elapsed() >= interval).unwrap_or(true) {
+                        // Bytes per second
+                        let speed = (read as f64 / start.elapsed().as_secs_f64().max(0.001)) as usize;
+                        tx.send(Message::UpdateState(job_id, DownloadState::Downloading(read, size, speed))).await.ok();
+                        last_update = Some(Instant::now());
+                    }
                 },
                 Err(e) => {
                     tokio::fs::remove_file(path).await.ok();
-                    return Err(e);
+                    return Err(JobError::new(stage, id, e));
                 }
             }
         }
 
-        info!("Done downloading: {}", track.id.to_base62());
-        Ok((path, audio_format))
+        info!("Done downloading: {}", track.id);
+        Ok((path, audio_format, quality))
     }
     
-    fn download_track_stream(path: impl AsRef<Path>, encrypted: AudioFile, key: AudioKey) -> impl Stream<Item = Result<usize, SpotifyError>> {
+    fn download_track_stream(path: impl AsRef<Path>, mut decrypted: Box<dyn Read + Send>) -> impl Stream<Item = Result<usize, SpotifyError>> {
         try_stream! {
             let mut file = File::create(path).await?;
-            let mut decrypted = AudioDecrypt::new(key, encrypted);
-            // Skip (i guess encrypted shit)
-            let mut skip: [u8; 0xa7] = [0; 0xa7];
-            let mut decrypted = tokio::task::spawn_blocking(move || {
-                match decrypted.read_exact(&mut skip) {
-                    Ok(_) => Ok(decrypted),
-                    Err(e) => Err(e)
-                }
-            }).await??;
-           
+
             // Custom reader loop for decrypting
             loop {
                 // Blocking reader
-                let (d, read, mut buf) = tokio::task::spawn_blocking(move || {
+                let (d, read, mut buf) = logger::spawn_blocking(move || {
                     let mut buf = vec![0; 1024 * 64];
                     match decrypted.read(&mut buf) {
                         Ok(r) => Ok((decrypted, r, buf)),
@@ -470,32 +901,22 @@ impl DownloaderInternal {
     /// Download and convert to MP3
     fn download_track_convert_stream(
         path: impl AsRef<Path>, 
-        encrypted: AudioFile, 
-        key: AudioKey,
+        decrypted: Box<dyn Read + Send>,
         format: AudioFormat,
         quality: Quality
     ) -> impl Stream<Item = Result<usize, SpotifyError>> {
         try_stream! {
             let mut file = File::create(path).await?;
-            let mut decrypted = AudioDecrypt::new(key, encrypted);
-            // Skip (i guess encrypted shit)
-            let mut skip: [u8; 0xa7] = [0; 0xa7];
-            let decrypted = tokio::task::spawn_blocking(move || {
-                match decrypted.read_exact(&mut skip) {
-                    Ok(_) => Ok(decrypted),
-                    Err(e) => Err(e)
-                }
-            }).await??;
-           
+
             // Convertor
-            let mut decrypted = tokio::task::spawn_blocking(move || {
-                AudioConverter::new(Box::new(decrypted), format, quality)
+            let mut decrypted = logger::spawn_blocking(move || {
+                AudioConverter::new(decrypted, format, quality)
             }).await??;
 
             // Custom reader loop for decrypting
             loop {
                 // Blocking reader
-                let (d, read, mut buf) = tokio::task::spawn_blocking(move || {
+                let (d, read, mut buf) = logger::spawn_blocking(move || {
                     let mut buf = vec![0; 1024 * 64];
                     match decrypted.read(&mut buf) {
                         Ok(r) => Ok((decrypted, r, buf)),
@@ -577,7 +998,8 @@ impl Quality {
 #[derive(Debug, Clone)]
 pub struct DownloadJob {
     pub id: i64,
-    pub track_id: String
+    pub track_id: String,
+    pub track: Option<Arc<aspotify::Track>>
 }
 
 
@@ -590,7 +1012,36 @@ pub enum Message {
     AddToQueue(Vec<Download>),
     // Get all downloads to UI
     GetDownloads,
-    SetConfig(DownloaderConfig)
+    SetConfig(DownloaderConfig),
+    // Download got lower quality than requested
+    Downgraded(i64, Quality),
+    // Job failed because of dropped session, retry after reconnect
+    Requeue(i64, JobError),
+    Move(i64, QueueMove),
+    // Output path taken by other job or earlier download
+    Duplicate(i64),
+    Shutdown
+}
+
+/// Queue changes pushed to subscribers
+#[derive(Debug, Clone)]
+pub enum DownloaderEvent {
+    Added(Vec<Download>),
+    StateChanged(i64, DownloadState),
+    /// Id, downloaded bytes, total bytes, bytes per second
+    Progress(i64, usize, usize, usize),
+    Removed(i64),
+    /// New queue order, IDs and priorities
+    Reordered(Vec<(i64, i32)>),
+    /// Id, quality received instead of requested one
+    Downgraded(i64, Quality),
+    Stats(QueueStats)
+}
+
+/// Manual queue reordering
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueMove {
+    Top, Up, Down
 }
 
 #[derive(Debug, Clone)]
@@ -604,17 +1055,42 @@ pub struct Download {
     pub track_id: String,
     pub title: String,
     pub subtitle: String,
-    pub state: DownloadState
+    pub state: DownloadState,
+    /// Prefetched metadata
+    pub track: Option<Arc<aspotify::Track>>,
+    /// Merged over global config when dispatched
+    pub overrides: Option<DownloadOverrides>,
+    /// Higher is downloaded first, then queue order
+    pub priority: i32,
+    /// Output path without extension, resolved when added if metadata was prefetched
+    pub path: Option<PathBuf>,
+    /// Quality received if lower than requested
+    pub quality: Option<Quality>
+}
+
+impl Download {
+    /// Get config for this download
+    pub fn config(&self, config: &DownloaderConfig) -> DownloaderConfig {
+        match &self.overrides {
+            Some(overrides) => overrides.apply(config),
+            None => config.clone()
+        }
+    }
 }
 
 impl Into<Download> for aspotify::Track {
     fn into(self) -> Download {
         Download {
             id: 0,
-            track_id: self.id.unwrap(),
-            title: self.name,
+            track_id: self.id.clone().unwrap(),
+            title: self.name.to_string(),
             subtitle: self.artists.first().map(|a| a.name.to_owned()).unwrap_or(String::new()),
-            state: DownloadState::None
+            state: DownloadState::None,
+            track: Some(Arc::new(self)),
+            overrides: None,
+            priority: 0,
+            path: None,
+            quality: None
         }
     }
 }
@@ -627,6 +1103,11 @@ impl Into<Download> for aspotify::TrackSimplified {
             title: self.name,
             subtitle: self.artists.first().map(|a| a.name.to_owned()).unwrap_or(String::new()),
             state: DownloadState::None,
+            track: None,
+            overrides: None,
+            priority: 0,
+            path: None,
+            quality: None
         }
     }
 }
@@ -635,7 +1116,8 @@ impl Into<DownloadJob> for Download {
     fn into(self) -> DownloadJob {
         DownloadJob {
             id: self.id,
-            track_id: self.track_id
+            track_id: self.track_id,
+            track: self.track
         }
     }
 }
@@ -644,20 +1126,142 @@ impl Into<DownloadJob> for Download {
 pub enum DownloadState {
     None,
     Lock,
-    Downloading(usize, usize),
+    /// Downloaded bytes, total bytes, bytes per second
+    Downloading(usize, usize, usize),
     Post,
     Done,
-    Error(String)
+    Error(JobError)
+}
+
+/// Stage at which a download job failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JobStage {
+    Metadata, Key, Download, Convert, Tag
+}
+
+impl ToString for JobStage {
+    fn to_string(&self) -> String {
+        match self {
+            JobStage::Metadata => "Metadata",
+            JobStage::Key => "Key",
+            JobStage::Download => "Download",
+            JobStage::Convert => "Convert",
+            JobStage::Tag => "Tag",
+        }.to_string()
+    }
+}
+
+/// Failed download job
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JobError {
+    pub stage: JobStage,
+    pub error: SpotifyError,
+    pub track_id: String,
+    pub retryable: bool
+}
+
+impl JobError {
+    /// Create new instance, retryability is derived from error
+    pub fn new(stage: JobStage, track_id: &str, error: SpotifyError) -> JobError {
+        JobError {
+            stage,
+            retryable: error.is_retryable(),
+            error,
+            track_id: track_id.to_string()
+        }
+    }
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} stage failed: {}", self.stage.to_string(), self.error)
+    }
+}
+
+/// Attach job stage to errors
+trait JobStageExt<T> {
+    fn stage(self, stage: JobStage, track_id: &str) -> Result<T, JobError>;
+}
+
+impl<T, E: Into<SpotifyError>> JobStageExt<T> for Result<T, E> {
+    fn stage(self, stage: JobStage, track_id: &str) -> Result<T, JobError> {
+        self.map_err(|e| JobError::new(stage, track_id, e.into()))
+    }
+}
+
+/// Failed download entry in exported report
+#[derive(Debug, Clone, Serialize)]
+pub struct JobErrorReport {
+    pub track_id: String,
+    pub title: String,
+    pub subtitle: String,
+    pub stage: JobStage,
+    pub kind: String,
+    pub message: String,
+    pub retryable: bool
+}
+
+impl JobErrorReport {
+    fn new(download: &Download, error: &JobError) -> JobErrorReport {
+        JobErrorReport {
+            track_id: error.track_id.to_string(),
+            title: download.title.to_string(),
+            subtitle: download.subtitle.to_string(),
+            stage: error.stage,
+            kind: error.error.kind().to_string(),
+            message: error.error.to_string(),
+            retryable: error.retryable
+        }
+    }
+}
+
+impl DownloadState {
+    /// Estimated remaining download time
+    pub fn eta(&self) -> Option<Duration> {
+        match self {
+            DownloadState::Downloading(r, t, s) if *s > 0 && t > r => Some(Duration::from_secs(((t - r) / s) as u64)),
+            _ => None
+        }
+    }
+}
+
+/// Aggregate queue and session statistics
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    pub pending: usize,
+    pub downloading: usize,
+    pub failed: usize,
+    /// Finished this session
+    pub done: usize,
+    /// Downloaded this session
+    pub bytes: usize,
+    /// Current speed of all jobs, bytes per second
+    pub speed: usize,
+    /// Average speed this session, bytes per second
+    pub average_speed: usize,
+    /// Finished this session with lower than requested quality
+    pub downgraded: usize,
+    /// Skipped as duplicates this session
+    pub duplicates: usize
 }
 
 /// Bitrate of music
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Copy)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, Copy)]
 pub enum Quality {
     Q320, Q256, Q160, Q96
 }
 
 impl Quality {
     pub const ALL: [Quality; 4] = [Quality::Q320, Quality::Q256, Quality::Q160, Quality::Q96];
+
+    /// Limit to max quality
+    pub fn cap(&self, max: Quality) -> Quality {
+        let index = |q: &Quality| Quality::ALL.iter().position(|a| a == q).unwrap();
+        match index(self) < index(&max) {
+            true => max,
+            false => *self
+        }
+    }
 }
 
 impl ToString for Quality {
@@ -671,7 +1275,101 @@ impl ToString for Quality {
     }
 }
 
+/// How to handle tags already present in file
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Copy)]
+pub enum TagPolicy {
+    /// Remove all existing tags
+    Replace,
+    /// Only write fields which are missing
+    Merge,
+    /// Overwrite written fields, keep the rest
+    Overwrite
+}
+
+impl TagPolicy {
+    pub const ALL: [TagPolicy; 3] = [TagPolicy::Replace, TagPolicy::Merge, TagPolicy::Overwrite];
+}
+
+impl ToString for TagPolicy {
+    fn to_string(&self) -> String {
+        match self {
+            TagPolicy::Replace => "Replace all",
+            TagPolicy::Merge => "Merge missing",
+            TagPolicy::Overwrite => "Overwrite written",
+        }.to_string()
+    }
+}
+
+/// User defined tag mappings, raw tag name and template per format
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct CustomTags {
+    pub id3: Vec<(String, String)>,
+    pub mp4: Vec<(String, String)>,
+    pub ogg: Vec<(String, String)>,
+    /// Built-in fields which won't be written
+    pub disabled: Vec<Field>
+}
+
+/// Per item config, unset fields use global config
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DownloadOverrides {
+    pub quality: Option<Quality>,
+    pub path: Option<PathBuf>,
+    pub filename_template: Option<String>,
+    pub convert_to_mp3: Option<bool>,
+    /// Queue priority, not part of config
+    pub priority: Option<i32>
+}
+
+impl DownloadOverrides {
+    /// Override names, same as config fields, and priority
+    pub const KEYS: [&'static str; 5] = ["quality", "path", "filename_template", "convert_to_mp3", "priority"];
+
+    /// Merge over config
+    pub fn apply(&self, config: &DownloaderConfig) -> DownloaderConfig {
+        let mut config = config.clone();
+        if let Some(quality) = self.quality {
+            config.quality = quality;
+        }
+        if let Some(path) = &self.path {
+            config.path = path.clone();
+        }
+        if let Some(template) = &self.filename_template {
+            config.filename_template = template.to_string();
+        }
+        if let Some(convert) = self.convert_to_mp3 {
+            config.convert_to_mp3 = convert;
+        }
+        config
+    }
+
+    /// Set override from string value
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), SpotifyError> {
+        match key {
+            "quality" => self.quality = Some(serde_json::from_value(serde_json::Value::String(value.to_string()))
+                .map_err(|_| SpotifyError::Error(format!("Invalid quality: {}", value)))?),
+            "path" => self.path = Some(PathBuf::from(value)),
+            "filename_template" => self.filename_template = Some(value.to_string()),
+            "convert_to_mp3" => self.convert_to_mp3 = Some(value.parse()
+                .map_err(|_| SpotifyError::Error(format!("Invalid convert_to_mp3: {}", value)))?),
+            "priority" => self.priority = Some(value.parse()
+                .map_err(|_| SpotifyError::Error(format!("Invalid priority: {}", value)))?),
+            _ => return Err(SpotifyError::Error(format!("Unknown download override: {}", key)))
+        }
+        Ok(())
+    }
+
+    /// All fields unset
+    pub fn is_empty(&self) -> bool {
+        self == &DownloadOverrides::default()
+    }
+}
+
+/// Missing fields are filled with defaults, so older settings files stay valid
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default = "DownloaderConfig::fallback")]
 pub struct DownloaderConfig {
     pub concurrent_downloads: usize,
     pub quality: Quality,
@@ -679,19 +1377,284 @@ pub struct DownloaderConfig {
     pub filename_template: String,
     pub id3v24: bool,
     pub convert_to_mp3: bool,
-    pub separator: String
+    pub separator: String,
+    pub tag_policy: TagPolicy,
+    pub custom_tags: CustomTags,
+    pub cache_on_disk: bool,
+    /// Minimal interval between progress updates in ms
+    pub progress_interval: u64
 }
 
 impl DownloaderConfig {
     pub fn default() -> Option<DownloaderConfig> {
-        Some(DownloaderConfig {
+        Some(DownloaderConfig::with_path(dirs::audio_dir()?.join("SpotiDown")))
+    }
+
+    /// Default config, home or current folder if there is no audio folder
+    pub fn fallback() -> DownloaderConfig {
+        DownloaderConfig::default().unwrap_or_else(|| {
+            DownloaderConfig::with_path(dirs::home_dir().unwrap_or_default().join("SpotiDown"))
+        })
+    }
+
+    /// Default config with custom download path
+    fn with_path(path: PathBuf) -> DownloaderConfig {
+        DownloaderConfig {
             concurrent_downloads: 4,
             quality: Quality::Q320,
-            path: dirs::audio_dir()?.join("SpotiDown"),
+            path,
             filename_template: "%artist% - %title%".to_string(),
             id3v24: true,
             convert_to_mp3: false,
-            separator: ", ".to_string()
-        })
+            separator: ", ".to_string(),
+            tag_policy: TagPolicy::Overwrite,
+            custom_tags: CustomTags::default(),
+            cache_on_disk: false,
+            progress_interval: 200
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::broadcast;
+
+    use crate::cache::Cache;
+    use crate::history::History;
+    use crate::mock::{MemoryProvider, MockServer};
+    use crate::spotify::WebMetadata;
+    use crate::tag::{Field, Tag, TagWrap};
+    use super::{AudioFormat, Download, DownloadOverrides, DownloadState, Downloader, DownloaderConfig, DownloaderEvent, DownloaderInternal, QueueMove, QueueStats, move_download};
+
+    const ALBUM: &str = "1FixtureAlbum000000001";
+    const TRACK: &str = "2FixtureTrack000000001";
+
+    /// Provider serving fixture album, every track is silent Ogg Vorbis
+    fn memory_provider() -> MemoryProvider {
+        let mut provider = MemoryProvider::default();
+        let audio = std::fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("fixtures").join("silence.ogg")).unwrap();
+        for name in ["track_1.json", "track_2.json", "track_3.json"] {
+            let mut track: aspotify::Track = MockServer::fixture(name);
+            // Don't download covers
+            track.album.images.clear();
+            let id = track.id.clone().unwrap();
+            provider.audio.insert(id.clone(), audio.clone());
+            provider.tracks.insert(id, track);
+        }
+        // Full tracklist, first page of fixture has only 2 tracks
+        let mut album: aspotify::Album = MockServer::fixture("album.json");
+        let mut items = vec![];
+        for name in ["album_tracks_0.json", "album_tracks_2.json"] {
+            let page: aspotify::Page<aspotify::TrackSimplified> = MockServer::fixture(name);
+            items.extend(page.items);
+        }
+        album.tracks.items = items;
+        provider.albums.insert(album.id.to_string(), album);
+        provider
+    }
+
+    /// Downloader with in-memory providers, cache and history, downloading to temp folder
+    fn downloader(test: &str) -> (Downloader, PathBuf) {
+        let folder = std::env::temp_dir().join(format!("spotidown-{}-{}", std::process::id(), test));
+        let provider = Arc::new(memory_provider());
+        let config = DownloaderConfig::with_path(folder.clone());
+        let downloader = Downloader::with_providers(config, provider.clone(), "memory", provider, Cache::with_folder(None), History::new(None));
+        (downloader, folder)
+    }
+
+    /// Wait for stats matching condition
+    async fn wait_for(events: &mut broadcast::Receiver<DownloaderEvent>, condition: impl Fn(&QueueStats) -> bool) -> QueueStats {
+        tokio::time::timeout(Duration::from_secs(60), async {
+            loop {
+                if let Ok(DownloaderEvent::Stats(stats)) = events.recv().await {
+                    if condition(&stats) {
+                        return stats;
+                    }
+                }
+            }
+        }).await.unwrap()
+    }
+
+    /// Wait until count downloads finished and nothing is pending or downloading
+    async fn wait_finished(events: &mut broadcast::Receiver<DownloaderEvent>, count: usize) -> QueueStats {
+        wait_for(events, |s| s.pending == 0 && s.downloading == 0 && s.done + s.failed >= count).await
+    }
+
+    #[tokio::test]
+    async fn download_and_tag_album() {
+        let (downloader, folder) = downloader("e2e-ogg");
+        let mut events = downloader.subscribe();
+        downloader.add_uri(&format!("spotify:album:{}", ALBUM)).await.unwrap();
+        let stats = wait_finished(&mut events, 3).await;
+        assert_eq!((stats.done, stats.failed), (3, 0));
+
+        let mut wrap = TagWrap::new(folder.join("Fixture Artist - SecondSong.ogg"), AudioFormat::OGG).unwrap();
+        let tag = wrap.get_tag();
+        assert_eq!(tag.get_field(&Field::Title), vec!["Second/Song"]);
+        assert_eq!(tag.get_field(&Field::TrackNumber), vec!["2"]);
+        assert_eq!(tag.get_field(&Field::TrackTotal), vec!["3"]);
+        assert_eq!(tag.get_field(&Field::DiscTotal), vec!["2"]);
+        assert_eq!(tag.get_field(&Field::ISRC), vec!["CZFIX2100002"]);
+        assert_eq!(tag.get_field(&Field::Label), vec!["Fixture Records"]);
+        assert!(folder.join("Fixture Artist - First Song.ogg").exists());
+        assert!(folder.join("Fixture Artist - Third Song.ogg").exists());
+        downloader.shutdown().await;
+        std::fs::remove_dir_all(folder).ok();
+    }
+
+    #[tokio::test]
+    async fn download_convert_and_tag_track() {
+        let (downloader, folder) = downloader("e2e-mp3");
+        let mut events = downloader.subscribe();
+        let overrides = DownloadOverrides { convert_to_mp3: Some(true), ..Default::default() };
+        downloader.add_uri_with(&format!("spotify:track:{}", TRACK), Some(overrides)).await.unwrap();
+        let stats = wait_finished(&mut events, 1).await;
+        assert_eq!((stats.done, stats.failed), (1, 0));
+
+        let path = folder.join("Fixture Artist - First Song.mp3");
+        let mut wrap = TagWrap::new(&path, AudioFormat::MP3).unwrap();
+        let tag = wrap.get_tag();
+        assert_eq!(tag.get_field(&Field::Title), vec!["First Song"]);
+        assert_eq!(tag.get_field(&Field::Artist), vec!["Fixture Artist"]);
+        assert_eq!(tag.get_field(&Field::Explicit), vec!["1"]);
+        assert!(tag.get_release_date().is_some());
+        downloader.shutdown().await;
+        std::fs::remove_dir_all(folder).ok();
+    }
+
+    #[tokio::test]
+    async fn filename_from_template() {
+        let server = MockServer::start().await;
+        let metadata = WebMetadata::new("client", "secret", server.urls.clone(), reqwest::Client::new());
+        let id = "2FixtureTrack000000002";
+        let track = metadata.get_track(id).await.unwrap();
+        let album = metadata.get_album(track.album.id.as_ref().unwrap()).await.unwrap();
+
+        let mut config = DownloaderConfig::with_path(PathBuf::from("/music"));
+        config.filename_template = "%albumArtist%/%album%/%0disc%-%0track% %title% [%id%]".to_string();
+        let values = DownloaderInternal::template_values(id, &track, &album);
+        // Values are sanitized, template separators are kept
+        assert_eq!(
+            DownloaderInternal::output_path(&config, &values),
+            PathBuf::from("/music/Fixture Artist/Fixture Album/01-02 SecondSong [2FixtureTrack000000002]")
+        );
+
+        config.filename_template = "%artists% - %title% (%isrc%, %label%)".to_string();
+        let values = DownloaderInternal::template_values(id, &track, &album);
+        assert_eq!(
+            DownloaderInternal::output_path(&config, &values),
+            PathBuf::from("/music/Fixture Artist - SecondSong (CZFIX2100002, Fixture Records)")
+        );
+    }
+
+    #[tokio::test]
+    async fn skip_duplicate_paths() {
+        let (downloader, folder) = downloader("e2e-duplicates");
+        let mut events = downloader.subscribe();
+        let album = format!("spotify:album:{}", ALBUM);
+        downloader.add_uri(&album).await.unwrap();
+        downloader.add_uri(&album).await.unwrap();
+        let stats = wait_finished(&mut events, 3).await;
+        assert_eq!((stats.done, stats.failed, stats.duplicates), (3, 0, 3));
+
+        // Downloaded before
+        downloader.add_uri(&album).await.unwrap();
+        downloader.add_uri(&format!("spotify:track:{}", TRACK)).await.unwrap();
+        let stats = wait_for(&mut events, |s| s.duplicates == 7).await;
+        assert_eq!((stats.done, stats.pending), (3, 0));
+        downloader.shutdown().await;
+        std::fs::remove_dir_all(folder).ok();
+    }
+
+    #[tokio::test]
+    async fn skip_tracks_in_history() {
+        let (downloader, folder) = downloader("e2e-history");
+        let mut events = downloader.subscribe();
+        let track = format!("spotify:track:{}", TRACK);
+        downloader.add_uri(&track).await.unwrap();
+        let stats = wait_finished(&mut events, 1).await;
+        assert_eq!((stats.done, stats.failed), (1, 0));
+
+        // Different template gives different path, track is still downloaded
+        let overrides = DownloadOverrides { filename_template: Some("%title%".to_string()), ..Default::default() };
+        downloader.add_uri_with(&track, Some(overrides)).await.unwrap();
+        let stats = wait_for(&mut events, |s| s.duplicates == 1).await;
+        assert_eq!((stats.done, stats.pending), (1, 0));
+        assert!(!folder.join("First Song.ogg").exists());
+        downloader.shutdown().await;
+        std::fs::remove_dir_all(folder).ok();
+    }
+
+    #[tokio::test]
+    async fn priority_item_starts_next() {
+        // No free slots until queue is filled
+        let folder = std::env::temp_dir().join(format!("spotidown-{}-e2e-priority", std::process::id()));
+        let provider = Arc::new(memory_provider());
+        let mut config = DownloaderConfig::with_path(folder.clone());
+        config.concurrent_downloads = 0;
+        let downloader = Downloader::with_providers(config, provider.clone(), "memory", provider, Cache::with_folder(None), History::new(None));
+        let mut events = downloader.subscribe();
+
+        let album = format!("spotify:album:{}", ALBUM);
+        for i in 0..10 {
+            let overrides = DownloadOverrides { path: Some(folder.join(i.to_string())), ..Default::default() };
+            downloader.add_uri_with(&album, Some(overrides)).await.unwrap();
+        }
+        let overrides = DownloadOverrides { path: Some(folder.join("priority")), priority: Some(1), ..Default::default() };
+        downloader.add_uri_with(&format!("spotify:track:{}", TRACK), Some(overrides)).await.unwrap();
+        let priority = downloader.get_downloads().await.into_iter().find(|d| d.priority == 1).unwrap().id;
+        downloader.set_account_limit("memory", 1);
+
+        // First job to report progress, post processing or finish
+        let started = tokio::time::timeout(Duration::from_secs(60), async {
+            loop {
+                match events.recv().await {
+                    Ok(DownloaderEvent::Progress(id, ..))
+                    | Ok(DownloaderEvent::StateChanged(id, DownloadState::Post))
+                    | Ok(DownloaderEvent::Removed(id)) => return id,
+                    _ => {}
+                }
+            }
+        }).await.unwrap();
+        assert_eq!(started, priority);
+        // Batch has same 3 tracks in each folder, downloaded ones are skipped
+        let stats = wait_for(&mut events, |s| s.pending == 0 && s.downloading == 0 && s.done + s.duplicates == 31).await;
+        assert_eq!((stats.done, stats.failed), (3, 0));
+        downloader.shutdown().await;
+        std::fs::remove_dir_all(folder).ok();
+    }
+
+    fn pending(id: i64, priority: i32) -> Download {
+        Download {
+            id,
+            track_id: id.to_string(),
+            title: String::new(),
+            subtitle: String::new(),
+            state: DownloadState::None,
+            track: None,
+            overrides: None,
+            priority,
+            path: None,
+            quality: None
+        }
+    }
+
+    fn order(queue: &[Download]) -> Vec<(i64, i32)> {
+        queue.iter().map(|d| (d.id, d.priority)).collect()
+    }
+
+    #[test]
+    fn move_in_queue() {
+        let mut queue = vec![pending(1, 5), pending(2, 5), pending(3, 0)];
+        move_download(&mut queue, 2, QueueMove::Top);
+        assert_eq!(order(&queue), vec![(3, 6), (1, 5), (2, 5)]);
+        move_download(&mut queue, 0, QueueMove::Down);
+        assert_eq!(order(&queue), vec![(1, 5), (3, 5), (2, 5)]);
+        move_download(&mut queue, 2, QueueMove::Up);
+        assert_eq!(order(&queue), vec![(1, 5), (2, 5), (3, 5)]);
     }
 }
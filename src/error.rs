@@ -1,6 +1,6 @@
 use std::fmt;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum SpotifyError {
     Error(String),
     IoError(std::io::ErrorKind, String),
@@ -12,13 +12,66 @@ pub enum SpotifyError {
     AudioKeyError,
     JoinError,
     ASpotify(String),
+    /// Retry-After in seconds
+    RateLimited(Option<u64>),
     Serde(String, usize, usize),
     InvalidUri,
     ParseError(url::ParseError),
     ID3Error(String, String),
     Reqwest(String),
     MP4AMeta(String, String),
-    InvalidFormat
+    InvalidFormat,
+    /// Secrets are encrypted and passphrase is missing or wrong
+    Locked,
+    /// Output file already downloaded or written by another job
+    Duplicate
+}
+
+impl SpotifyError {
+    /// Variant name, for filtering and reports
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SpotifyError::Error(_) => "Error",
+            SpotifyError::IoError(_, _) => "IoError",
+            SpotifyError::MercuryError => "MercuryError",
+            SpotifyError::AuthenticationError => "AuthenticationError",
+            SpotifyError::Unavailable => "Unavailable",
+            SpotifyError::SpotifyIdError => "SpotifyIdError",
+            SpotifyError::ChannelError => "ChannelError",
+            SpotifyError::AudioKeyError => "AudioKeyError",
+            SpotifyError::JoinError => "JoinError",
+            SpotifyError::ASpotify(_) => "ASpotify",
+            SpotifyError::RateLimited(_) => "RateLimited",
+            SpotifyError::Serde(_, _, _) => "Serde",
+            SpotifyError::InvalidUri => "InvalidUri",
+            SpotifyError::ParseError(_) => "ParseError",
+            SpotifyError::ID3Error(_, _) => "ID3Error",
+            SpotifyError::Reqwest(_) => "Reqwest",
+            SpotifyError::MP4AMeta(_, _) => "MP4AMeta",
+            SpotifyError::InvalidFormat => "InvalidFormat",
+            SpotifyError::Locked => "Locked",
+            SpotifyError::Duplicate => "Duplicate"
+        }
+    }
+
+    /// Whether retrying might succeed (network or transient server errors)
+    pub fn is_retryable(&self) -> bool {
+        matches!(self,
+            SpotifyError::IoError(_, _) |
+            SpotifyError::MercuryError |
+            SpotifyError::ChannelError |
+            SpotifyError::AudioKeyError |
+            SpotifyError::ASpotify(_) |
+            SpotifyError::RateLimited(_) |
+            SpotifyError::Reqwest(_)
+        )
+    }
+
+    /// Error caused by dropped librespot session, audio key errors are also returned
+    /// for tracks without key, so they don't count
+    pub fn is_session(&self) -> bool {
+        matches!(self, SpotifyError::MercuryError | SpotifyError::ChannelError)
+    }
 }
 
 /// std
@@ -36,13 +89,16 @@ impl fmt::Display for SpotifyError {
             SpotifyError::AudioKeyError => write!(f, "Audio Key Error"),
             SpotifyError::JoinError => write!(f, "Tokio Join Error"),
             SpotifyError::ASpotify(e) => write!(f, "Spotify Error: {}", e),
+            SpotifyError::RateLimited(_) => write!(f, "Rate Limited"),
             SpotifyError::Serde(e, l, c) => write!(f, "Serde Error @{}:{} {}", l, c, e),
             SpotifyError::InvalidUri => write!(f, "Invalid URI"),
             SpotifyError::ParseError(e) => write!(f, "Parse Error: {}", e),
             SpotifyError::ID3Error(k, e) => write!(f, "ID3 Error: {} {}", k, e),
             SpotifyError::Reqwest(e) => write!(f, "Reqwest Error: {}", e),
             SpotifyError::MP4AMeta(k, e) => write!(f, "MP4AMeta Error: {} {}", k, e),
-            SpotifyError::InvalidFormat => write!(f, "Invalid Format!")
+            SpotifyError::InvalidFormat => write!(f, "Invalid Format!"),
+            SpotifyError::Locked => write!(f, "Secrets are locked, passphrase required!"),
+            SpotifyError::Duplicate => write!(f, "Duplicate download!")
         }
     }
 }
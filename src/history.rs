@@ -0,0 +1,126 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use crate::downloader::Quality;
+use crate::error::SpotifyError;
+
+/// Finished downloads and paths of running jobs, used to skip duplicates
+#[derive(Debug, Clone)]
+pub struct History {
+    inner: Arc<Mutex<HistoryInner>>,
+    /// Output paths of running jobs, without extension
+    active: Arc<Mutex<HashSet<PathBuf>>>,
+    file: Option<PathBuf>
+}
+
+#[derive(Debug, Default)]
+struct HistoryInner {
+    /// Output path without extension and with it
+    paths: HashMap<PathBuf, PathBuf>,
+    /// Track ID and paths it was downloaded to
+    tracks: HashMap<String, Vec<PathBuf>>
+}
+
+/// Line of history file
+#[derive(Debug, Serialize, Deserialize)]
+struct HistoryEntry {
+    track_id: String,
+    path: PathBuf,
+    /// Quality received, missing in older entries
+    #[serde(default)]
+    quality: Option<Quality>
+}
+
+impl History {
+    /// Load from default history file
+    pub fn load() -> History {
+        History::new(History::get_path())
+    }
+
+    /// Load from history file, missing or invalid lines are skipped, None keeps history in memory
+    pub fn new(file: Option<PathBuf>) -> History {
+        let mut inner = HistoryInner::default();
+        if let Some(data) = file.as_ref().and_then(|f| std::fs::read_to_string(f).ok()) {
+            for line in data.lines().filter(|l| !l.trim().is_empty()) {
+                match serde_json::from_str::<HistoryEntry>(line) {
+                    Ok(entry) => inner.insert(entry.track_id, entry.path),
+                    Err(e) => warn!("Invalid download history entry: {}", e)
+                }
+            }
+        }
+        History {
+            inner: Arc::new(Mutex::new(inner)),
+            active: Arc::new(Mutex::new(HashSet::new())),
+            file
+        }
+    }
+
+    /// Get path to history file
+    pub fn get_path() -> Option<PathBuf> {
+        Some(dirs::config_dir()?.join("spotidown").join("history.jsonl"))
+    }
+
+    /// File downloaded to path without extension still exists
+    pub fn contains_path(&self, path: &Path) -> bool {
+        self.inner.lock().unwrap().paths.get(path).map(|p| p.exists()).unwrap_or(false)
+    }
+
+    /// Track was downloaded before and some of its files still exist
+    pub fn contains_track(&self, track_id: &str) -> bool {
+        self.inner.lock().unwrap().tracks.get(track_id).map(|p| p.iter().any(|p| p.exists())).unwrap_or(false)
+    }
+
+    /// Reserve output path without extension for running job, None if already taken
+    pub fn claim(&self, path: &Path) -> Option<PathClaim> {
+        match self.active.lock().unwrap().insert(path.to_owned()) {
+            true => Some(PathClaim { active: self.active.clone(), path: path.to_owned() }),
+            false => None
+        }
+    }
+
+    /// Record finished download, path with extension
+    pub async fn add(&self, track_id: &str, path: &Path, quality: Quality) {
+        self.inner.lock().unwrap().insert(track_id.to_string(), path.to_owned());
+        let entry = HistoryEntry { track_id: track_id.to_string(), path: path.to_owned(), quality: Some(quality) };
+        if let Err(e) = self.append(&entry).await {
+            warn!("Failed saving download history: {}", e);
+        }
+    }
+
+    /// Append line to history file
+    async fn append(&self, entry: &HistoryEntry) -> Result<(), SpotifyError> {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return Ok(())
+        };
+        tokio::fs::create_dir_all(file.parent().unwrap()).await?;
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let mut f = tokio::fs::OpenOptions::new().create(true).append(true).open(file).await?;
+        f.write_all(&line).await?;
+        Ok(())
+    }
+}
+
+impl HistoryInner {
+    fn insert(&mut self, track_id: String, path: PathBuf) {
+        self.paths.insert(path.with_extension(""), path.clone());
+        self.tracks.entry(track_id).or_default().push(path);
+    }
+}
+
+/// Output path reserved by running job, released on drop
+#[derive(Debug)]
+pub struct PathClaim {
+    active: Arc<Mutex<HashSet<PathBuf>>>,
+    path: PathBuf
+}
+
+impl Drop for PathClaim {
+    fn drop(&mut self) {
+        self.active.lock().unwrap().remove(&self.path);
+    }
+}
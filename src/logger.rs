@@ -0,0 +1,185 @@
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use chrono::Local;
+use log::{LevelFilter, Log, Metadata, Record};
+use tokio::task::JoinHandle;
+
+/// Max log file size before rotating
+const MAX_LOG_SIZE: u64 = 5 * 1024 * 1024;
+/// Number of log files kept, including current one
+const MAX_LOG_FILES: usize = 3;
+/// Max bytes of log included in bug report
+const MAX_REPORT_SIZE: usize = 256 * 1024;
+/// Default level for spotidown, other crates are capped to warn
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Debug;
+
+/// Current level for spotidown targets
+static LEVEL: AtomicUsize = AtomicUsize::new(DEFAULT_LEVEL as usize);
+
+tokio::task_local! {
+    /// Job ID and track ID of currently running download job
+    static JOB: (i64, String);
+}
+
+/// Logs to stderr and rotating file in settings folder
+struct Logger {
+    folder: Option<PathBuf>,
+    file: Mutex<Option<(File, u64)>>
+}
+
+impl Logger {
+    /// Get path of log file, index > 0 for rotated files
+    fn path(&self, index: usize) -> Option<PathBuf> {
+        let name = match index {
+            0 => "spotidown.log".to_string(),
+            i => format!("spotidown.log.{}", i)
+        };
+        Some(self.folder.as_ref()?.join(name))
+    }
+
+    /// Open log file for appending
+    fn open(&self) -> Option<(File, u64)> {
+        let path = self.path(0)?;
+        std::fs::create_dir_all(path.parent()?).ok()?;
+        let file = OpenOptions::new().create(true).append(true).open(&path).ok()?;
+        let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+        Some((file, size))
+    }
+
+    /// Shift old log files and start new one
+    fn rotate(&self) {
+        for i in (0..MAX_LOG_FILES - 1).rev() {
+            if let (Some(from), Some(to)) = (self.path(i), self.path(i + 1)) {
+                std::fs::rename(from, to).ok();
+            }
+        }
+    }
+
+    fn write_file(&self, line: &str) {
+        let mut lock = self.file.lock().unwrap();
+        if lock.as_ref().map(|(_, size)| *size > MAX_LOG_SIZE).unwrap_or(false) {
+            *lock = None;
+            self.rotate();
+        }
+        if lock.is_none() {
+            *lock = self.open();
+        }
+        if let Some((file, size)) = lock.as_mut() {
+            if file.write_all(line.as_bytes()).is_ok() {
+                *size += line.len() as u64;
+            }
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let level = level_from_usize(LEVEL.load(Ordering::Relaxed));
+        match metadata.target().starts_with("spotidown") {
+            true => metadata.level() <= level,
+            false => metadata.level() <= level.min(LevelFilter::Warn)
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let job = JOB.try_with(|(id, track_id)| format!(" [job {} {}]", id, track_id)).unwrap_or_default();
+        let line = format!(
+            "{} {:5} {}{} {}\n",
+            Local::now().format("%Y-%m-%d %H:%M:%S%.3f"), record.level(), record.target(), job, record.args()
+        );
+        eprint!("{}", line);
+        self.write_file(&line);
+    }
+
+    fn flush(&self) {
+        if let Some((file, _)) = self.file.lock().unwrap().as_mut() {
+            file.flush().ok();
+        }
+    }
+}
+
+/// Initialize global logger, SPOTIDOWN_LOG env var overrides level
+pub fn init(folder: Option<PathBuf>) {
+    let logger = Box::leak(Box::new(Logger {
+        folder,
+        file: Mutex::new(None)
+    }));
+    log::set_logger(logger).ok();
+    set_level(None);
+}
+
+/// Set spotidown log level from settings, env var takes precedence
+pub fn set_level(level: Option<&str>) {
+    let level = std::env::var("SPOTIDOWN_LOG").ok().as_deref().or(level)
+        .and_then(|l| LevelFilter::from_str(l).ok())
+        .unwrap_or(DEFAULT_LEVEL);
+    LEVEL.store(level as usize, Ordering::Relaxed);
+    log::set_max_level(level);
+}
+
+/// Run future with job context attached to log lines
+pub async fn with_job<F: Future>(id: i64, track_id: &str, f: F) -> F::Output {
+    JOB.scope((id, track_id.to_string()), f).await
+}
+
+/// Run blocking closure on blocking thread, job context of caller is kept
+pub fn spawn_blocking<F, R>(f: F) -> JoinHandle<R>
+where F: FnOnce() -> R + Send + 'static, R: Send + 'static {
+    let job = JOB.try_with(|job| job.clone()).ok();
+    tokio::task::spawn_blocking(move || match job {
+        Some(job) => JOB.sync_scope(job, f),
+        None => f()
+    })
+}
+
+/// Get system info and recent log lines for bug report
+pub async fn bug_report(folder: Option<PathBuf>) -> String {
+    let mut log = vec![];
+    if let Some(folder) = folder {
+        // Oldest first
+        for name in &["spotidown.log.1", "spotidown.log"] {
+            if let Ok(data) = tokio::fs::read(folder.join(name)).await {
+                log.extend(data);
+            }
+        }
+    }
+    let start = log.len().saturating_sub(MAX_REPORT_SIZE);
+    format!(
+        "SpotiDown {} ({} {})\n\n{}",
+        env!("CARGO_PKG_VERSION"), std::env::consts::OS, std::env::consts::ARCH, String::from_utf8_lossy(&log[start..])
+    )
+}
+
+fn level_from_usize(level: usize) -> LevelFilter {
+    match level {
+        0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        _ => LevelFilter::Trace
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::JOB;
+
+    #[tokio::test]
+    async fn job_context_in_blocking_closure() {
+        let job = super::with_job(1, "track", async {
+            super::spawn_blocking(|| JOB.try_with(|job| job.clone()).ok()).await.unwrap()
+        }).await;
+        assert_eq!(job, Some((1, "track".to_string())));
+        let job = super::spawn_blocking(|| JOB.try_with(|job| job.clone()).ok()).await.unwrap();
+        assert_eq!(job, None);
+    }
+}
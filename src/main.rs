@@ -1,15 +1,48 @@
 #[macro_use] extern crate log;
 
+mod cache;
 mod downloader;
 mod error;
 mod spotify;
 mod ui;
 mod tag;
 mod converter;
+mod web_api;
+mod logger;
+mod secrets;
+mod pool;
+mod proxy;
+mod config;
+mod history;
+#[cfg(test)]
+mod mock;
 
 fn main() {
-    std::env::set_var("RUST_LOG", "spotidown=debug,warn");
-    pretty_env_logger::init();
+    logger::init(ui::Settings::get_folder());
+
+    let overrides = match config::Overrides::from_env() {
+        Ok(overrides) => overrides,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, config::Overrides::usage());
+            std::process::exit(2);
+        }
+    };
+    if overrides.help {
+        print!("{}", config::Overrides::usage());
+        return;
+    }
+    // Print config and layer of each value
+    if overrides.show_config {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        match runtime.block_on(ui::Settings::describe()) {
+            Ok(config) => print!("{}", config),
+            Err(e) => {
+                eprintln!("Failed loading config: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
 
     ui::start_ui().unwrap();
 }
\ No newline at end of file
@@ -0,0 +1,198 @@
+use aspotify::{Album, Playlist, Track, TrackSimplified};
+use librespot::core::spotify_id::FileId;
+use librespot::metadata::FileFormat;
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use url::Url;
+
+use crate::error::SpotifyError;
+use crate::spotify::{AudioFiles, AudioProvider, MetadataProvider, ProviderFuture, SpotifyItem};
+use crate::web_api::ApiUrls;
+
+/// Local Web API stand-in serving canned JSON from tests/fixtures/api, stopped on drop
+pub struct MockServer {
+    pub urls: ApiUrls,
+    task: JoinHandle<()>
+}
+
+impl MockServer {
+    /// Listen on random local port
+    pub async fn start() -> MockServer {
+        let folder = MockServer::folder();
+        let routes: HashMap<String, String> = serde_json::from_slice(&std::fs::read(folder.join("routes.json")).unwrap()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let routes = routes.clone();
+                let folder = folder.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = MockServer::handle(stream, &routes, &folder).await {
+                        warn!("Mock server request failed: {}", e);
+                    }
+                });
+            }
+        });
+        MockServer {
+            urls: ApiUrls {
+                api: format!("{}/v1", base),
+                token: format!("{}/api/token", base)
+            },
+            task
+        }
+    }
+
+    /// Folder with fixture JSON and routes.json
+    pub fn folder() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("fixtures").join("api")
+    }
+
+    /// Parse fixture file
+    pub fn fixture<T: serde::de::DeserializeOwned>(name: &str) -> T {
+        serde_json::from_slice(&std::fs::read(MockServer::folder().join(name)).unwrap()).unwrap()
+    }
+
+    /// Serve single request, routes are keyed by path and decoded query
+    async fn handle(mut stream: TcpStream, routes: &HashMap<String, String>, folder: &Path) -> Result<(), SpotifyError> {
+        // Head and body
+        let mut data = vec![];
+        let mut buf = [0u8; 4096];
+        let head_end = loop {
+            if let Some(i) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+                break i + 4;
+            }
+            let read = stream.read(&mut buf).await?;
+            if read == 0 {
+                return Ok(());
+            }
+            data.extend_from_slice(&buf[..read]);
+        };
+        let head = String::from_utf8_lossy(&data[..head_end]).to_string();
+        let length: usize = head.lines()
+            .find_map(|l| l.to_lowercase().strip_prefix("content-length:").map(|v| v.trim().to_string()))
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+        while data.len() < head_end + length {
+            let read = stream.read(&mut buf).await?;
+            if read == 0 {
+                break;
+            }
+            data.extend_from_slice(&buf[..read]);
+        }
+
+        let target = head.split(' ').nth(1).unwrap_or("/");
+        let url = Url::parse(&format!("http://mock{}", target))?;
+        let mut key = url.path().to_string();
+        let query: Vec<String> = url.query_pairs().map(|(k, v)| format!("{}={}", k, v)).collect();
+        if !query.is_empty() {
+            key = format!("{}?{}", key, query.join("&"));
+        }
+
+        let mut headers = String::new();
+        let (status, body) = match key.as_str() {
+            "/api/token" => ("200 OK", br#"{"access_token":"mock","token_type":"Bearer","expires_in":3600}"#.to_vec()),
+            "/v1/tracks/ratelimited" => {
+                headers.push_str("Retry-After: 1\r\n");
+                ("429 Too Many Requests", br#"{"error":{"status":429,"message":"API rate limit exceeded"}}"#.to_vec())
+            }
+            _ => match routes.get(&key) {
+                Some(file) => ("200 OK", tokio::fs::read(folder.join(file)).await?),
+                None => {
+                    warn!("Mock server has no route for {}", key);
+                    ("404 Not Found", br#"{"error":{"status":404,"message":"Not found"}}"#.to_vec())
+                }
+            }
+        };
+        let head = format!("HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n", status, body.len(), headers);
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(&body).await?;
+        stream.shutdown().await?;
+        Ok(())
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// In-memory provider serving fixture metadata and unencrypted Ogg files
+#[derive(Debug, Clone, Default)]
+pub struct MemoryProvider {
+    pub tracks: HashMap<String, Track>,
+    pub albums: HashMap<String, Album>,
+    pub playlists: HashMap<String, (Playlist, Vec<Track>)>,
+    /// Track ID and Ogg Vorbis file data
+    pub audio: HashMap<String, Vec<u8>>
+}
+
+impl MemoryProvider {
+    fn get<T: Clone>(map: &HashMap<String, T>, id: &str) -> Result<T, SpotifyError> {
+        map.get(id).cloned().ok_or(SpotifyError::Unavailable)
+    }
+}
+
+impl MetadataProvider for MemoryProvider {
+    fn resolve_uri<'a>(&'a self, uri: &'a str) -> ProviderFuture<'a, SpotifyItem> {
+        Box::pin(async move {
+            let parts = uri.split(":").skip(1).collect::<Vec<&str>>();
+            match (parts.get(0), parts.get(1)) {
+                (Some(&"track"), Some(id)) => Ok(SpotifyItem::Track(MemoryProvider::get(&self.tracks, id)?)),
+                (Some(&"album"), Some(id)) => Ok(SpotifyItem::Album(MemoryProvider::get(&self.albums, id)?)),
+                (Some(&"playlist"), Some(id)) => Ok(SpotifyItem::Playlist(MemoryProvider::get(&self.playlists, id)?.0)),
+                _ => Ok(SpotifyItem::Other(uri.to_string()))
+            }
+        })
+    }
+
+    fn get_track<'a>(&'a self, id: &'a str) -> ProviderFuture<'a, Track> {
+        Box::pin(async move { MemoryProvider::get(&self.tracks, id) })
+    }
+
+    fn get_album<'a>(&'a self, id: &'a str) -> ProviderFuture<'a, Album> {
+        Box::pin(async move { MemoryProvider::get(&self.albums, id) })
+    }
+
+    fn get_tracks<'a>(&'a self, ids: &'a [&'a str]) -> ProviderFuture<'a, Vec<Track>> {
+        Box::pin(async move { Ok(ids.iter().filter_map(|id| self.tracks.get(*id).cloned()).collect()) })
+    }
+
+    fn get_albums<'a>(&'a self, ids: &'a [&'a str]) -> ProviderFuture<'a, Vec<Album>> {
+        Box::pin(async move { Ok(ids.iter().filter_map(|id| self.albums.get(*id).cloned()).collect()) })
+    }
+
+    fn full_playlist<'a>(&'a self, id: &'a str) -> ProviderFuture<'a, Vec<Track>> {
+        Box::pin(async move { Ok(MemoryProvider::get(&self.playlists, id)?.1) })
+    }
+
+    fn full_album<'a>(&'a self, id: &'a str) -> ProviderFuture<'a, Vec<TrackSimplified>> {
+        Box::pin(async move { Ok(MemoryProvider::get(&self.albums, id)?.tracks.items) })
+    }
+}
+
+impl AudioProvider for MemoryProvider {
+    fn audio_files<'a>(&'a self, id: &'a str) -> ProviderFuture<'a, AudioFiles> {
+        Box::pin(async move {
+            if !self.audio.contains_key(id) {
+                return Err(SpotifyError::Unavailable);
+            }
+            let mut files = HashMap::new();
+            files.insert(FileFormat::OGG_VORBIS_320, FileId([0; 20]));
+            Ok(AudioFiles { id: id.to_string(), files })
+        })
+    }
+
+    fn open_file<'a>(&'a self, files: &'a AudioFiles, _format: FileFormat) -> ProviderFuture<'a, (Box<dyn Read + Send>, usize)> {
+        Box::pin(async move {
+            let data = MemoryProvider::get(&self.audio, &files.id)?;
+            let size = data.len();
+            let reader: Box<dyn Read + Send> = Box::new(Cursor::new(data));
+            Ok((reader, size))
+        })
+    }
+}
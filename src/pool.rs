@@ -0,0 +1,273 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::sync::futures::Notified;
+
+use crate::error::SpotifyError;
+use crate::spotify::{AudioProvider, ConnectionState};
+
+/// How long an account is out of rotation after authentication errors
+const ACCOUNT_COOLDOWN: Duration = Duration::from_secs(120);
+/// Max wait before rechecking accounts
+const MAX_WAIT: Duration = Duration::from_secs(5);
+
+/// Audio sources of all logged in accounts, jobs are spread across healthy ones
+#[derive(Clone)]
+pub struct AccountPool {
+    members: Arc<Mutex<Vec<Arc<PoolMember>>>>,
+    // Round robin start
+    next: Arc<AtomicUsize>,
+    // Wakes waiting jobs when slot is released or accounts change
+    notify: Arc<Notify>,
+    // Accounts connected after shutdown are closed right away
+    closed: Arc<AtomicBool>
+}
+
+struct PoolMember {
+    name: String,
+    audio: Arc<dyn AudioProvider>,
+    limit: AtomicUsize,
+    slots: Arc<Semaphore>,
+    retired: Mutex<Retired>,
+    cooldown: Mutex<Option<Instant>>
+}
+
+/// Slots taken out of use after limit was lowered
+#[derive(Default)]
+struct Retired {
+    permits: Vec<OwnedSemaphorePermit>,
+    // Busy slots to retire once their jobs finish
+    pending: usize
+}
+
+impl PoolMember {
+    /// Remaining cooldown
+    fn cooldown(&self) -> Option<Duration> {
+        let until = (*self.cooldown.lock().unwrap())?;
+        until.checked_duration_since(Instant::now())
+    }
+}
+
+impl AccountPool {
+    /// Create new empty instance
+    pub fn new() -> AccountPool {
+        AccountPool {
+            members: Arc::new(Mutex::new(vec![])),
+            next: Arc::new(AtomicUsize::new(0)),
+            notify: Arc::new(Notify::new()),
+            closed: Arc::new(AtomicBool::new(false))
+        }
+    }
+
+    /// Add account or replace one with same name
+    pub fn add(&self, name: &str, audio: Arc<dyn AudioProvider>, limit: usize) {
+        if self.closed.load(Ordering::Relaxed) {
+            audio.shutdown();
+            return;
+        }
+        let member = Arc::new(PoolMember {
+            name: name.to_string(),
+            audio,
+            limit: AtomicUsize::new(limit),
+            slots: Arc::new(Semaphore::new(limit)),
+            retired: Mutex::new(Retired::default()),
+            cooldown: Mutex::new(None)
+        });
+        let mut members = self.members.lock().unwrap();
+        members.retain(|m| m.name != name);
+        members.push(member);
+        info!("Account {} added to download pool ({} slots)", name, limit);
+        self.notify.notify_waiters();
+    }
+
+    /// Change concurrency limit of account, running jobs keep their slots until they finish
+    pub fn set_limit(&self, name: &str, limit: usize) {
+        if let Some(m) = self.members.lock().unwrap().iter().find(|m| m.name == name) {
+            let old = m.limit.swap(limit, Ordering::Relaxed);
+            let mut retired = m.retired.lock().unwrap();
+            if limit > old {
+                // Cancel pending retirements, then bring back retired slots
+                let mut add = limit - old;
+                let cancel = add.min(retired.pending);
+                retired.pending -= cancel;
+                add -= cancel;
+                while add > 0 && retired.permits.pop().is_some() {
+                    add -= 1;
+                }
+                m.slots.add_permits(add);
+            } else {
+                for _ in limit..old {
+                    match m.slots.clone().try_acquire_owned() {
+                        Ok(permit) => retired.permits.push(permit),
+                        Err(_) => retired.pending += 1
+                    }
+                }
+            }
+        }
+        self.notify.notify_waiters();
+    }
+
+    /// Remove all accounts and close their connections
+    pub fn shutdown(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        let members = std::mem::take(&mut *self.members.lock().unwrap());
+        for member in members {
+            member.audio.shutdown();
+        }
+        self.notify.notify_waiters();
+    }
+
+    /// Whether pool was shut down
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    /// Resolves when accounts, limits or free slots change, create before checking to not miss changes
+    pub fn changed(&self) -> Notified<'_> {
+        self.notify.notified()
+    }
+
+    /// Total concurrency limit of all accounts
+    pub fn capacity(&self) -> usize {
+        self.members.lock().unwrap().iter().map(|m| m.limit.load(Ordering::Relaxed)).sum()
+    }
+
+    /// Wait for free slot on healthy account, fails if pool was shut down
+    pub async fn acquire(&self) -> Result<Lease, SpotifyError> {
+        loop {
+            // Created before checking so releases in between aren't missed
+            let notified = self.notify.notified();
+            let members = self.members.lock().unwrap().clone();
+            if members.is_empty() {
+                if self.is_closed() {
+                    return Err(SpotifyError::Error("Download pool is shut down".into()));
+                }
+                warn!("No accounts in download pool, waiting for one to connect");
+                notified.await;
+                continue;
+            }
+            let start = self.next.fetch_add(1, Ordering::Relaxed);
+            let mut wait = MAX_WAIT;
+            for i in 0..members.len() {
+                let member = &members[(start + i) % members.len()];
+                if let Some(cooldown) = member.cooldown() {
+                    wait = wait.min(cooldown);
+                    continue;
+                }
+                // Reconnecting
+                if member.audio.connection() != ConnectionState::Connected {
+                    continue;
+                }
+                if let Ok(permit) = member.slots.clone().try_acquire_owned() {
+                    return Ok(Lease {
+                        member: member.clone(),
+                        notify: self.notify.clone(),
+                        permit: Some(permit)
+                    });
+                }
+            }
+            tokio::select! {
+                _ = notified => {},
+                _ = tokio::time::sleep(wait) => {}
+            }
+        }
+    }
+}
+
+impl Default for AccountPool {
+    fn default() -> Self {
+        AccountPool::new()
+    }
+}
+
+impl fmt::Debug for AccountPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<AccountPool {} accounts>", self.members.lock().unwrap().len())
+    }
+}
+
+/// Slot on account, released on drop
+pub struct Lease {
+    member: Arc<PoolMember>,
+    notify: Arc<Notify>,
+    permit: Option<OwnedSemaphorePermit>
+}
+
+impl Lease {
+    /// Get audio source of account
+    pub fn audio(&self) -> &dyn AudioProvider {
+        self.member.audio.as_ref()
+    }
+
+    /// Take account out of rotation on authentication errors, check connection on session errors
+    pub fn report(&self, error: &SpotifyError) {
+        match error {
+            SpotifyError::AuthenticationError => {
+                warn!("Account {} out of rotation for {}s: {}", self.member.name, ACCOUNT_COOLDOWN.as_secs(), error);
+                *self.member.cooldown.lock().unwrap() = Some(Instant::now() + ACCOUNT_COOLDOWN);
+            }
+            e if e.is_session() => self.member.audio.session_error(),
+            _ => {}
+        }
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        // Release before waking waiters, unless slot was removed by lowering limit
+        if let Some(permit) = self.permit.take() {
+            let mut retired = self.member.retired.lock().unwrap();
+            if retired.pending > 0 {
+                retired.pending -= 1;
+                retired.permits.push(permit);
+            }
+        }
+        self.notify.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    use crate::mock::MemoryProvider;
+    use super::AccountPool;
+
+    #[tokio::test]
+    async fn set_limit_resizes_slots() {
+        let pool = AccountPool::new();
+        pool.add("a", Arc::new(MemoryProvider::default()), 2);
+        let first = pool.acquire().await.unwrap();
+        let second = pool.acquire().await.unwrap();
+        // Busy slots are retired once released
+        pool.set_limit("a", 1);
+        assert_eq!(pool.capacity(), 1);
+        drop(first);
+        assert!(timeout(Duration::from_millis(100), pool.acquire()).await.is_err());
+        drop(second);
+        let third = pool.acquire().await.unwrap();
+        // Retired slots come back
+        pool.set_limit("a", 3);
+        let _fourth = pool.acquire().await.unwrap();
+        let _fifth = pool.acquire().await.unwrap();
+        assert!(timeout(Duration::from_millis(100), pool.acquire()).await.is_err());
+        drop(third);
+        assert!(timeout(Duration::from_millis(100), pool.acquire()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn acquire_fails_after_shutdown() {
+        let pool = AccountPool::new();
+        let waiting = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.acquire().await.map(|_| ()) }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        pool.shutdown();
+        assert!(waiting.await.unwrap().is_err());
+    }
+}
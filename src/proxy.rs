@@ -0,0 +1,421 @@
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use url::Url;
+
+use crate::error::SpotifyError;
+use crate::secrets::{SecretStore, Secrets};
+
+/// Max size of HTTP request or response head
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+/// Default port of SOCKS5 proxies
+const SOCKS5_PORT: u16 = 1080;
+/// Ports of Spotify access points
+const ACCESS_POINT_PORTS: [u16; 3] = [80, 443, 4070];
+
+/// Proxy for librespot, Web API and cover downloads
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProxyConfig {
+    /// http:// or socks5:// URL without credentials, can point to a local proxy
+    pub url: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    /// Plaintext password entered in settings file, moved to secret store on load
+    #[serde(default, skip_serializing)]
+    pub password: Option<String>,
+    /// Where password is stored
+    #[serde(default)]
+    pub secret_store: Option<SecretStore>
+}
+
+impl ProxyConfig {
+    /// Move plaintext password to secret store or load stored one, returns true if settings changed
+    pub fn secure_password(&mut self) -> Result<bool, SpotifyError> {
+        match (&self.password, self.secret_store) {
+            (Some(password), _) => {
+                info!("Moving proxy password to secret store");
+                self.secret_store = Some(Secrets::save_proxy_password(password)?);
+                Ok(true)
+            }
+            (None, Some(store)) => {
+                self.password = Some(Secrets::load_proxy_password(store)?);
+                Ok(false)
+            }
+            (None, None) => Ok(false)
+        }
+    }
+
+    /// Parse and validate URL
+    fn parse(&self) -> Result<Url, SpotifyError> {
+        let url = Url::parse(&self.url)?;
+        match url.scheme() {
+            "http" | "socks5" => Ok(url),
+            s => Err(SpotifyError::Error(format!("Unsupported proxy scheme: {}", s)))
+        }
+    }
+
+    /// Get username and password, if set
+    fn auth(&self) -> Option<(&str, &str)> {
+        let username = self.username.as_deref().filter(|u| !u.is_empty())?;
+        Some((username, self.password.as_deref().unwrap_or("")))
+    }
+
+    /// Create HTTP client using proxy
+    pub fn client(proxy: Option<&ProxyConfig>) -> Result<Client, SpotifyError> {
+        let proxy = match proxy {
+            Some(p) => p,
+            None => return Ok(Client::new())
+        };
+        let mut url = proxy.parse()?;
+        let mut reqwest_proxy = match (url.scheme(), proxy.auth()) {
+            // reqwest reads SOCKS5 credentials from URL
+            ("socks5", Some((username, password))) => {
+                url.set_username(username).ok();
+                url.set_password(Some(password)).ok();
+                reqwest::Proxy::all(url.as_str())?
+            }
+            _ => reqwest::Proxy::all(url.as_str())?
+        };
+        if let ("http", Some((username, password))) = (url.scheme(), proxy.auth()) {
+            reqwest_proxy = reqwest_proxy.basic_auth(username, password);
+        }
+        Ok(Client::builder().proxy(reqwest_proxy).build()?)
+    }
+
+    /// Get proxy URL for librespot, which only supports plain HTTP proxies,
+    /// so authenticated and SOCKS5 proxies go through local relay
+    pub async fn librespot(&self) -> Result<(Url, Option<Relay>), SpotifyError> {
+        let url = self.parse()?;
+        if url.scheme() == "http" && self.auth().is_none() {
+            return Ok((url, None));
+        }
+        let (relay, url) = Relay::start(self.clone()).await?;
+        Ok((url, Some(relay)))
+    }
+
+    /// Open tunnel to host:port through proxy
+    pub async fn connect(&self, target: &str) -> Result<TcpStream, SpotifyError> {
+        let url = self.parse()?;
+        let host = url.host_str().ok_or(SpotifyError::Error("Missing proxy host!".into()))?;
+        let port = url.port_or_known_default().unwrap_or(SOCKS5_PORT);
+        let mut stream = TcpStream::connect((host, port)).await?;
+        match url.scheme() {
+            "socks5" => self.socks5_connect(&mut stream, target).await?,
+            _ => self.http_connect(&mut stream, target).await?
+        }
+        Ok(stream)
+    }
+
+    /// HTTP CONNECT handshake
+    async fn http_connect(&self, stream: &mut TcpStream, target: &str) -> Result<(), SpotifyError> {
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+        if let Some((username, password)) = self.auth() {
+            let auth = base64::encode(format!("{}:{}", username, password));
+            request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", auth));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        let head = read_head(stream).await?;
+        match head.split(' ').nth(1) {
+            Some("200") => Ok(()),
+            Some("407") => Err(SpotifyError::Error("Proxy authentication failed!".into())),
+            status => Err(SpotifyError::Error(format!("Proxy CONNECT failed: {}", status.unwrap_or("invalid response"))))
+        }
+    }
+
+    /// SOCKS5 handshake with optional username/password authentication
+    async fn socks5_connect(&self, stream: &mut TcpStream, target: &str) -> Result<(), SpotifyError> {
+        let auth = self.auth();
+        match auth {
+            Some(_) => stream.write_all(&[5, 2, 0, 2]).await?,
+            None => stream.write_all(&[5, 1, 0]).await?
+        }
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).await?;
+        match (reply[1], auth) {
+            (0, _) => {},
+            (2, Some((username, password))) => {
+                if username.len() > 255 || password.len() > 255 {
+                    return Err(SpotifyError::Error("Proxy credentials too long!".into()));
+                }
+                let mut request = vec![1, username.len() as u8];
+                request.extend(username.as_bytes());
+                request.push(password.len() as u8);
+                request.extend(password.as_bytes());
+                stream.write_all(&request).await?;
+                stream.read_exact(&mut reply).await?;
+                if reply[1] != 0 {
+                    return Err(SpotifyError::Error("Proxy authentication failed!".into()));
+                }
+            }
+            _ => return Err(SpotifyError::Error("SOCKS5 proxy rejected authentication methods!".into()))
+        }
+
+        // Connect by domain name
+        let (host, port) = target.rsplit_once(':').ok_or(SpotifyError::Error("Invalid proxy target!".into()))?;
+        let port: u16 = port.parse().map_err(|_| SpotifyError::Error("Invalid proxy target!".into()))?;
+        if host.len() > 255 {
+            return Err(SpotifyError::Error("Invalid proxy target!".into()));
+        }
+        let mut request = vec![5, 1, 0, 3, host.len() as u8];
+        request.extend(host.as_bytes());
+        request.extend(&port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let mut head = [0u8; 4];
+        stream.read_exact(&mut head).await?;
+        if head[1] != 0 {
+            return Err(SpotifyError::Error(format!("SOCKS5 connect failed: {}", head[1])));
+        }
+        // Skip bound address and port
+        let len = match head[3] {
+            1 => 4,
+            4 => 16,
+            _ => stream.read_u8().await? as usize
+        };
+        let mut skip = vec![0u8; len + 2];
+        stream.read_exact(&mut skip).await?;
+        Ok(())
+    }
+}
+
+/// Local HTTP CONNECT endpoint forwarding to Spotify access points through configured proxy, stopped on drop
+#[derive(Debug)]
+pub struct Relay {
+    task: JoinHandle<()>
+}
+
+impl Relay {
+    /// Listen on random local port, returns its URL
+    pub async fn start(proxy: ProxyConfig) -> Result<(Relay, Url), SpotifyError> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = Url::parse(&format!("http://{}", listener.local_addr()?))?;
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let proxy = proxy.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = Relay::handle(stream, &proxy).await {
+                        warn!("Proxy relay connection failed: {}", e);
+                    }
+                });
+            }
+        });
+        debug!("Proxy relay listening on {}", url);
+        Ok((Relay { task }, url))
+    }
+
+    /// Tunnel single CONNECT request
+    async fn handle(mut client: TcpStream, proxy: &ProxyConfig) -> Result<(), SpotifyError> {
+        let head = read_head(&mut client).await?;
+        let target = match head.strip_prefix("CONNECT ").and_then(|l| l.split(' ').next()) {
+            Some(target) => target.to_string(),
+            // librespot falls back to default access point
+            None => {
+                client.write_all(b"HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\n\r\n").await?;
+                return Ok(());
+            }
+        };
+        // Any local process can connect, don't let it use the proxy for other hosts
+        if !Relay::is_access_point(&target) {
+            client.write_all(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n").await?;
+            return Err(SpotifyError::Error(format!("Proxy relay target not allowed: {}", target)));
+        }
+        let mut upstream = match proxy.connect(&target).await {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                client.write_all(b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n").await?;
+                return Err(e);
+            }
+        };
+        client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").await?;
+        tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+        Ok(())
+    }
+
+    /// Target is host:port of Spotify access point
+    fn is_access_point(target: &str) -> bool {
+        let (host, port) = match target.rsplit_once(':') {
+            Some(target) => target,
+            None => return false
+        };
+        let host = host.to_lowercase();
+        (host == "spotify.com" || host.ends_with(".spotify.com"))
+            && port.parse().map(|p: u16| ACCESS_POINT_PORTS.contains(&p)).unwrap_or(false)
+    }
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Read HTTP head byte by byte, so no tunneled data is consumed
+async fn read_head<R: AsyncRead + Unpin>(stream: &mut R) -> Result<String, SpotifyError> {
+    let mut head = vec![];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD_SIZE {
+            return Err(SpotifyError::Error("Proxy response too large!".into()));
+        }
+        head.push(stream.read_u8().await?);
+    }
+    Ok(String::from_utf8_lossy(&head).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::task::JoinHandle;
+
+    use super::{read_head, ProxyConfig, Relay};
+
+    const TARGET: &str = "ap.spotify.com:443";
+
+    /// Proxy stand-in accepting single connection, returns requested target and echoes tunneled data
+    async fn stand_in(socks5: bool, auth: Option<(&'static str, &'static str)>) -> (ProxyConfig, JoinHandle<Option<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("{}://{}", if socks5 { "socks5" } else { "http" }, listener.local_addr().unwrap());
+        let task = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let target = match socks5 {
+                true => socks5_handshake(&mut stream, auth).await?,
+                false => http_handshake(&mut stream, auth).await?
+            };
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+            Some(target)
+        });
+        let config = ProxyConfig {
+            url,
+            username: auth.map(|a| a.0.to_string()),
+            password: auth.map(|a| a.1.to_string()),
+            secret_store: None
+        };
+        (config, task)
+    }
+
+    async fn http_handshake(stream: &mut TcpStream, auth: Option<(&str, &str)>) -> Option<String> {
+        let head = read_head(stream).await.unwrap();
+        let target = head.strip_prefix("CONNECT ")?.split(' ').next()?.to_string();
+        if let Some((username, password)) = auth {
+            let header = format!("Proxy-Authorization: Basic {}\r\n", base64::encode(format!("{}:{}", username, password)));
+            if !head.contains(&header) {
+                stream.write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n").await.unwrap();
+                return None;
+            }
+        }
+        stream.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").await.unwrap();
+        Some(target)
+    }
+
+    async fn socks5_handshake(stream: &mut TcpStream, auth: Option<(&str, &str)>) -> Option<String> {
+        let mut head = [0u8; 2];
+        stream.read_exact(&mut head).await.unwrap();
+        let mut methods = vec![0u8; head[1] as usize];
+        stream.read_exact(&mut methods).await.unwrap();
+        match auth {
+            Some((username, password)) => {
+                assert!(methods.contains(&2));
+                stream.write_all(&[5, 2]).await.unwrap();
+                // Version, then length prefixed username and password
+                assert_eq!(stream.read_u8().await.unwrap(), 1);
+                let mut credentials = vec![];
+                for _ in 0..2 {
+                    let mut value = vec![0u8; stream.read_u8().await.unwrap() as usize];
+                    stream.read_exact(&mut value).await.unwrap();
+                    credentials.push(String::from_utf8(value).unwrap());
+                }
+                let valid = credentials == [username, password];
+                stream.write_all(&[1, if valid { 0 } else { 1 }]).await.unwrap();
+                if !valid {
+                    return None;
+                }
+            }
+            None => stream.write_all(&[5, 0]).await.unwrap()
+        }
+        let mut request = [0u8; 5];
+        stream.read_exact(&mut request).await.unwrap();
+        assert_eq!(&request[..4], &[5, 1, 0, 3]);
+        let mut host = vec![0u8; request[4] as usize];
+        stream.read_exact(&mut host).await.unwrap();
+        let port = stream.read_u16().await.unwrap();
+        stream.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0]).await.unwrap();
+        Some(format!("{}:{}", String::from_utf8(host).unwrap(), port))
+    }
+
+    /// Data goes through tunnel
+    async fn echo(stream: &mut TcpStream) {
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn http_connect() {
+        let (config, task) = stand_in(false, None).await;
+        echo(&mut config.connect(TARGET).await.unwrap()).await;
+        assert_eq!(task.await.unwrap().as_deref(), Some(TARGET));
+    }
+
+    #[tokio::test]
+    async fn http_connect_auth() {
+        let (config, task) = stand_in(false, Some(("user", "pass:word"))).await;
+        echo(&mut config.connect(TARGET).await.unwrap()).await;
+        assert_eq!(task.await.unwrap().as_deref(), Some(TARGET));
+
+        let (mut config, _task) = stand_in(false, Some(("user", "pass:word"))).await;
+        config.password = Some("wrong".to_string());
+        assert!(config.connect(TARGET).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn socks5_connect() {
+        let (config, task) = stand_in(true, None).await;
+        echo(&mut config.connect(TARGET).await.unwrap()).await;
+        assert_eq!(task.await.unwrap().as_deref(), Some(TARGET));
+    }
+
+    #[tokio::test]
+    async fn socks5_connect_auth() {
+        let (config, task) = stand_in(true, Some(("user", "pass"))).await;
+        echo(&mut config.connect(TARGET).await.unwrap()).await;
+        assert_eq!(task.await.unwrap().as_deref(), Some(TARGET));
+
+        let (mut config, _task) = stand_in(true, Some(("user", "pass"))).await;
+        config.password = Some("wrong".to_string());
+        assert!(config.connect(TARGET).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn relay_tunnels_access_points_only() {
+        let (config, task) = stand_in(true, Some(("user", "pass"))).await;
+        let (_relay, url) = Relay::start(config).await.unwrap();
+        let address = (url.host_str().unwrap(), url.port().unwrap());
+
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client.write_all(b"CONNECT example.com:443 HTTP/1.1\r\n\r\n").await.unwrap();
+        assert!(read_head(&mut client).await.unwrap().starts_with("HTTP/1.1 403"));
+
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n", TARGET).as_bytes()).await.unwrap();
+        assert!(read_head(&mut client).await.unwrap().starts_with("HTTP/1.1 200"));
+        echo(&mut client).await;
+        assert_eq!(task.await.unwrap().as_deref(), Some(TARGET));
+    }
+
+    #[test]
+    fn access_points() {
+        assert!(Relay::is_access_point("ap.spotify.com:443"));
+        assert!(Relay::is_access_point("gew1-accesspoint-b-abcd.ap.spotify.com:4070"));
+        assert!(!Relay::is_access_point("spotify.com.example.com:443"));
+        assert!(!Relay::is_access_point("evilspotify.com:443"));
+        assert!(!Relay::is_access_point("ap.spotify.com:22"));
+        assert!(!Relay::is_access_point("ap.spotify.com"));
+    }
+}
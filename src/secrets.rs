@@ -0,0 +1,352 @@
+use std::path::PathBuf;
+use sanitize_filename::sanitize;
+use aes_ctr::Aes128Ctr;
+use aes_ctr::cipher::generic_array::GenericArray;
+use aes_ctr::cipher::{NewStreamCipher, SyncStreamCipher};
+use hmac::{Hmac, Mac, NewMac};
+use librespot::core::authentication::Credentials;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+
+use crate::error::SpotifyError;
+use crate::ui::Settings;
+
+/// PBKDF2 rounds for passphrase key derivation
+const PBKDF2_ROUNDS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 16;
+const MAC_LEN: usize = 20;
+/// Store name of proxy password, not a valid username
+const PROXY_SECRET: &str = "@proxy";
+/// Keyring service name
+#[cfg(feature = "keyring")]
+const KEYRING_SERVICE: &str = "spotidown";
+
+/// Where authentication secrets are stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SecretStore {
+    /// Platform secret service
+    Keyring,
+    /// File encrypted with passphrase
+    Encrypted,
+    /// File readable only by user
+    File
+}
+
+impl Default for SecretStore {
+    fn default() -> Self {
+        SecretStore::File
+    }
+}
+
+/// Authentication secrets, stored outside of settings.json
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Secrets {
+    /// librespot credentials, password only until first login of migrated accounts
+    pub credentials: Credentials,
+    pub client_secret: String
+}
+
+impl Secrets {
+    /// Create new instance
+    pub fn new(credentials: Credentials, client_secret: &str) -> Secrets {
+        Secrets {
+            credentials,
+            client_secret: client_secret.to_string()
+        }
+    }
+
+    /// Save to most secure available store, encrypted if passphrase is set
+    pub fn save(&self, passphrase: Option<&str>) -> Result<SecretStore, SpotifyError> {
+        let store = match passphrase {
+            Some(p) if !p.is_empty() => SecretStore::Encrypted,
+            _ if Secrets::keyring_available() => SecretStore::Keyring,
+            _ => SecretStore::File
+        };
+        self.save_to(store, passphrase)?;
+        Ok(store)
+    }
+
+    /// Save to given store and remove secrets from others
+    pub fn save_to(&self, store: SecretStore, passphrase: Option<&str>) -> Result<(), SpotifyError> {
+        Secrets::write(store, &self.credentials.username, &serde_json::to_vec(self)?, passphrase)
+    }
+
+    /// Save proxy password to most secure store not requiring passphrase
+    pub fn save_proxy_password(password: &str) -> Result<SecretStore, SpotifyError> {
+        let store = match Secrets::keyring_available() {
+            true => SecretStore::Keyring,
+            false => SecretStore::File
+        };
+        Secrets::write(store, PROXY_SECRET, password.as_bytes(), None)?;
+        Ok(store)
+    }
+
+    /// Load proxy password
+    pub fn load_proxy_password(store: SecretStore) -> Result<String, SpotifyError> {
+        Ok(String::from_utf8_lossy(&Secrets::read(store, PROXY_SECRET, None)?).to_string())
+    }
+
+    /// Write data to given store and remove it from others
+    fn write(store: SecretStore, name: &str, data: &[u8], passphrase: Option<&str>) -> Result<(), SpotifyError> {
+        match store {
+            SecretStore::Keyring => Secrets::keyring_set(name, &String::from_utf8_lossy(data))?,
+            SecretStore::Encrypted => {
+                let passphrase = passphrase.filter(|p| !p.is_empty()).ok_or(SpotifyError::Locked)?;
+                Secrets::write_private(&Secrets::path(store, name)?, &encrypt(data, passphrase))?;
+            }
+            SecretStore::File => Secrets::write_private(&Secrets::path(store, name)?, data)?
+        }
+        // Don't leave older copies around
+        for other in &[SecretStore::Keyring, SecretStore::Encrypted, SecretStore::File] {
+            if *other != store {
+                Secrets::delete(*other, name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Read data from store, passphrase required for encrypted store
+    fn read(store: SecretStore, name: &str, passphrase: Option<&str>) -> Result<Vec<u8>, SpotifyError> {
+        Ok(match store {
+            SecretStore::Keyring => Secrets::keyring_get(name)?.into_bytes(),
+            SecretStore::Encrypted => {
+                let passphrase = passphrase.filter(|p| !p.is_empty()).ok_or(SpotifyError::Locked)?;
+                decrypt(&std::fs::read(Secrets::path(store, name)?)?, passphrase)?
+            }
+            SecretStore::File => std::fs::read(Secrets::path(store, name)?)?
+        })
+    }
+
+    /// Remove account secrets from store
+    pub fn delete(store: SecretStore, username: &str) {
+        match store {
+            SecretStore::Keyring => Secrets::keyring_delete(username),
+            _ => if let Ok(path) = Secrets::path(store, username) {
+                std::fs::remove_file(path).ok();
+            }
+        }
+    }
+
+    /// Load from store, passphrase required for encrypted store
+    pub fn load(store: SecretStore, username: &str, passphrase: Option<&str>) -> Result<Secrets, SpotifyError> {
+        Ok(serde_json::from_slice(&Secrets::read(store, username, passphrase)?)?)
+    }
+
+    /// Get path of account secrets file
+    fn path(store: SecretStore, username: &str) -> Result<PathBuf, SpotifyError> {
+        let folder = Settings::get_folder().ok_or(SpotifyError::Error("No settings folder!".into()))?.join("secrets");
+        Ok(match store {
+            SecretStore::Encrypted => folder.join(format!("{}.enc", sanitize(username))),
+            _ => folder.join(format!("{}.json", sanitize(username)))
+        })
+    }
+
+    /// Write file readable only by current user
+    pub fn write_private(path: &PathBuf, data: &[u8]) -> Result<(), SpotifyError> {
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(path)?;
+        // Fix permissions of files created by older versions
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+        }
+        std::io::Write::write_all(&mut file, data)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "keyring")]
+    fn keyring_available() -> bool {
+        // Tests don't touch secret service
+        if cfg!(test) {
+            return false;
+        }
+        // Probe secret service
+        let entry = keyring::Keyring::new(KEYRING_SERVICE, "spotidown-probe");
+        let available = entry.set_password("probe").is_ok();
+        entry.delete_password().ok();
+        available
+    }
+
+    #[cfg(feature = "keyring")]
+    fn keyring_set(username: &str, data: &str) -> Result<(), SpotifyError> {
+        keyring::Keyring::new(KEYRING_SERVICE, username).set_password(data)
+            .map_err(|e| SpotifyError::Error(format!("Keyring: {}", e)))
+    }
+
+    #[cfg(feature = "keyring")]
+    fn keyring_get(username: &str) -> Result<String, SpotifyError> {
+        keyring::Keyring::new(KEYRING_SERVICE, username).get_password()
+            .map_err(|e| SpotifyError::Error(format!("Keyring: {}", e)))
+    }
+
+    #[cfg(feature = "keyring")]
+    fn keyring_delete(username: &str) {
+        keyring::Keyring::new(KEYRING_SERVICE, username).delete_password().ok();
+    }
+
+    #[cfg(not(feature = "keyring"))]
+    fn keyring_available() -> bool {
+        false
+    }
+
+    #[cfg(not(feature = "keyring"))]
+    fn keyring_set(_username: &str, _data: &str) -> Result<(), SpotifyError> {
+        Err(SpotifyError::Error("Keyring support not enabled!".into()))
+    }
+
+    #[cfg(not(feature = "keyring"))]
+    fn keyring_get(_username: &str) -> Result<String, SpotifyError> {
+        Err(SpotifyError::Error("Keyring support not enabled!".into()))
+    }
+
+    #[cfg(not(feature = "keyring"))]
+    fn keyring_delete(_username: &str) {}
+}
+
+/// Derive AES and HMAC keys from passphrase
+fn derive_keys(passphrase: &str, salt: &[u8]) -> ([u8; 16], [u8; 32]) {
+    let mut out = [0u8; 48];
+    pbkdf2::pbkdf2::<Hmac<Sha1>>(passphrase.as_bytes(), salt, PBKDF2_ROUNDS, &mut out);
+    let mut key = [0u8; 16];
+    let mut mac_key = [0u8; 32];
+    key.copy_from_slice(&out[..16]);
+    mac_key.copy_from_slice(&out[16..]);
+    (key, mac_key)
+}
+
+/// AES-128-CTR with HMAC-SHA1, output is salt + nonce + mac + ciphertext
+fn encrypt(data: &[u8], passphrase: &str) -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let salt: [u8; SALT_LEN] = rng.gen();
+    let nonce: [u8; NONCE_LEN] = rng.gen();
+    let (key, mac_key) = derive_keys(passphrase, &salt);
+
+    let mut ciphertext = data.to_vec();
+    Aes128Ctr::new(GenericArray::from_slice(&key), GenericArray::from_slice(&nonce)).apply_keystream(&mut ciphertext);
+    let mut mac = Hmac::<Sha1>::new_from_slice(&mac_key).unwrap();
+    mac.update(&nonce);
+    mac.update(&ciphertext);
+
+    let mut out = salt.to_vec();
+    out.extend(&nonce);
+    out.extend(mac.finalize().into_bytes());
+    out.extend(ciphertext);
+    out
+}
+
+/// Decrypt and verify, wrong passphrase returns Locked
+fn decrypt(data: &[u8], passphrase: &str) -> Result<Vec<u8>, SpotifyError> {
+    if data.len() < SALT_LEN + NONCE_LEN + MAC_LEN {
+        return Err(SpotifyError::Error("Invalid secrets file!".into()));
+    }
+    let (salt, rest) = data.split_at(SALT_LEN);
+    let (nonce, rest) = rest.split_at(NONCE_LEN);
+    let (tag, ciphertext) = rest.split_at(MAC_LEN);
+    let (key, mac_key) = derive_keys(passphrase, salt);
+
+    let mut mac = Hmac::<Sha1>::new_from_slice(&mac_key).unwrap();
+    mac.update(nonce);
+    mac.update(ciphertext);
+    mac.verify(tag).map_err(|_| SpotifyError::Locked)?;
+
+    let mut plaintext = ciphertext.to_vec();
+    Aes128Ctr::new(GenericArray::from_slice(&key), GenericArray::from_slice(nonce)).apply_keystream(&mut plaintext);
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use librespot::core::authentication::Credentials;
+    use serde_json::json;
+
+    use crate::error::SpotifyError;
+    use crate::ui::Settings;
+    use super::{SecretStore, Secrets, decrypt, encrypt};
+
+    #[test]
+    fn encrypt_round_trip() {
+        let data = b"{\"client_secret\":\"client-secret\"}";
+        let encrypted = encrypt(data, "passphrase");
+        assert!(!encrypted.windows(data.len()).any(|w| w == &data[..]));
+        assert_eq!(decrypt(&encrypted, "passphrase").unwrap(), data.to_vec());
+        // Random salt and nonce
+        assert_ne!(encrypt(data, "passphrase"), encrypted);
+    }
+
+    #[test]
+    fn wrong_passphrase_is_locked() {
+        let encrypted = encrypt(b"secret", "passphrase");
+        assert_eq!(decrypt(&encrypted, "other"), Err(SpotifyError::Locked));
+        assert_eq!(decrypt(&encrypted, ""), Err(SpotifyError::Locked));
+    }
+
+    #[test]
+    fn tampered_ciphertext_is_rejected() {
+        let encrypted = encrypt(b"secret", "passphrase");
+        // Salt, nonce, MAC and ciphertext
+        for i in &[0, 16, 32, encrypted.len() - 1] {
+            let mut tampered = encrypted.clone();
+            tampered[*i] ^= 1;
+            assert_eq!(decrypt(&tampered, "passphrase"), Err(SpotifyError::Locked));
+        }
+        assert!(decrypt(&encrypted[..40], "passphrase").is_err());
+    }
+
+    #[test]
+    fn encrypted_store_requires_passphrase() {
+        let secrets = Secrets::new(Credentials::with_password("encrypted-user", "hunter2"), "client-secret");
+        assert_eq!(secrets.save(Some("passphrase")).unwrap(), SecretStore::Encrypted);
+        let data = std::fs::read(Secrets::path(SecretStore::Encrypted, "encrypted-user").unwrap()).unwrap();
+        assert!(!String::from_utf8_lossy(&data).contains("client-secret"));
+
+        assert_eq!(Secrets::load(SecretStore::Encrypted, "encrypted-user", None).err(), Some(SpotifyError::Locked));
+        assert_eq!(Secrets::load(SecretStore::Encrypted, "encrypted-user", Some("other")).err(), Some(SpotifyError::Locked));
+        let loaded = Secrets::load(SecretStore::Encrypted, "encrypted-user", Some("passphrase")).unwrap();
+        assert_eq!(loaded.client_secret, "client-secret");
+        assert_eq!(loaded.credentials.username, "encrypted-user");
+        Secrets::delete(SecretStore::Encrypted, "encrypted-user");
+    }
+
+    #[tokio::test]
+    async fn migration_scrubs_plaintext_secrets() {
+        // Settings file of version without accounts
+        let path = Settings::get_path().unwrap();
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let v0 = json!({
+            "username": "migrated-user",
+            "password": "hunter2",
+            "client_id": "client",
+            "client_secret": "client-secret"
+        });
+        std::fs::write(&path, serde_json::to_vec(&v0).unwrap()).unwrap();
+
+        let settings = Settings::load().await.unwrap();
+        assert_eq!(settings.accounts[0].username, "migrated-user");
+        assert_eq!(settings.accounts[0].secret_store, SecretStore::File);
+
+        // Neither settings file nor backup contain secrets
+        let backup = path.with_extension("json.v0.bak");
+        for file in &[&path, &backup] {
+            let data = std::fs::read_to_string(file).unwrap();
+            assert!(data.contains("migrated-user"));
+            assert!(!data.contains("hunter2"), "password left in {:?}", file);
+            assert!(!data.contains("client-secret"), "client secret left in {:?}", file);
+        }
+        let secrets = Secrets::load(SecretStore::File, "migrated-user", None).unwrap();
+        assert_eq!(secrets.client_secret, "client-secret");
+
+        Secrets::delete(SecretStore::File, "migrated-user");
+        std::fs::remove_file(&path).ok();
+        std::fs::remove_file(&backup).ok();
+    }
+}
@@ -1,6 +1,7 @@
 use std::path::{PathBuf, Path};
 use chrono::{Datelike, NaiveDate};
 use id3::{Tag, Version};
+use id3::{Frame, Content};
 use id3::frame::{Picture, PictureType, Timestamp};
 
 use crate::error::SpotifyError;
@@ -8,7 +9,7 @@ use crate::error::SpotifyError;
 use super::Field;
 
 pub struct ID3Tag {
-    path: PathBuf, 
+    path: PathBuf,
     tag: Tag,
     separator: String,
     version: Version
@@ -34,6 +35,31 @@ impl ID3Tag {
             false => self.version = Version::Id3v23
         }
     }
+
+    /// Get frame ID for field, None for numbering fields which are stored as N/Total
+    fn field_frame(field: &Field) -> Option<&'static str> {
+        let frame = match field {
+            Field::Title => "TIT2",
+            Field::Artist => "TPE1",
+            Field::Album => "TALB",
+            Field::Genre => "TCON",
+            Field::Label => "TPUB",
+            Field::AlbumArtist => "TPE2",
+            Field::ISRC => "TSRC",
+            Field::Copyright => "TCOP",
+            Field::URL => "WOAS",
+            // TXXX
+            Field::UPC => "TXXX:BARCODE",
+            Field::Explicit => "TXXX:ITUNESADVISORY",
+            Field::Popularity => "TXXX:SPOTIFY_POPULARITY",
+            Field::TrackId => "TXXX:SPOTIFY_TRACK_ID",
+            Field::AlbumId => "TXXX:SPOTIFY_ALBUM_ID",
+            Field::ArtistId => "TXXX:SPOTIFY_ARTIST_ID",
+            Field::AlbumURL => "TXXX:SPOTIFY_ALBUM_URL",
+            Field::TrackNumber | Field::TrackTotal | Field::DiscNumber | Field::DiscTotal => return None
+        };
+        Some(frame)
+    }
 }
 
 impl super::Tag for ID3Tag {
@@ -42,21 +68,85 @@ impl super::Tag for ID3Tag {
     }
 
     fn set_raw(&mut self, tag: &str, value: Vec<String>) {
+        // TXXX:DESCRIPTION
+        if let Some(description) = tag.strip_prefix("TXXX:") {
+            self.tag.add_extended_text(description, value.join(&self.separator));
+            return;
+        }
+        // URL frames, only first value is used
+        if tag.starts_with('W') && tag != "WXXX" {
+            self.tag.remove(tag);
+            if let Some(url) = value.into_iter().next() {
+                self.tag.add_frame(Frame::with_content(tag, Content::Link(url)));
+            }
+            return;
+        }
         self.tag.set_text(tag, value.join(&self.separator));
     }
 
     fn set_field(&mut self, field: Field, value: Vec<String>) {
-        let tag = match field {
-            Field::Title => "TIT2",
-            Field::Artist => "TPE1",
-            Field::Album => "TALB",
-            Field::TrackNumber => "TPOS",
-            Field::DiscNumber => "TRCK",
-            Field::Genre => "TCON",
-            Field::Label => "TPUB",
-            Field::AlbumArtist => "TPE2",
+        if let Some(frame) = ID3Tag::field_frame(&field) {
+            self.set_raw(frame, value);
+            return;
+        }
+
+        // Numbering, stored as N/Total
+        let n = match value.first().and_then(|v| v.parse().ok()) {
+            Some(n) => n,
+            None => return
         };
-        self.set_raw(tag, value);
+        match field {
+            Field::TrackNumber => self.tag.set_track(n),
+            Field::TrackTotal => self.tag.set_total_tracks(n),
+            Field::DiscNumber => self.tag.set_disc(n),
+            _ => self.tag.set_total_discs(n)
+        }
+    }
+
+    fn get_raw(&self, tag: &str) -> Vec<String> {
+        // TXXX:DESCRIPTION
+        let value = if let Some(description) = tag.strip_prefix("TXXX:") {
+            self.tag.extended_texts().find(|t| t.description == description).map(|t| t.value.to_string())
+        } else {
+            self.tag.get(tag).and_then(|f| match f.content() {
+                Content::Text(t) => Some(t.to_string()),
+                Content::Link(l) => Some(l.to_string()),
+                _ => None
+            })
+        };
+        match value {
+            // ID3v2.4 null separated values
+            Some(v) => v.split('\0').map(|v| v.to_string()).collect(),
+            None => vec![]
+        }
+    }
+
+    fn get_field(&self, field: &Field) -> Vec<String> {
+        if let Some(frame) = ID3Tag::field_frame(field) {
+            return super::split_values(field, self.get_raw(frame), &self.separator);
+        }
+        let n = match field {
+            Field::TrackNumber => self.tag.track(),
+            Field::TrackTotal => self.tag.total_tracks(),
+            Field::DiscNumber => self.tag.disc(),
+            _ => self.tag.total_discs()
+        };
+        n.map(|n| vec![n.to_string()]).unwrap_or_default()
+    }
+
+    fn get_release_date(&self) -> Option<NaiveDate> {
+        let date = self.tag.date_recorded()?;
+        NaiveDate::from_ymd_opt(date.year, date.month.unwrap_or(1) as u32, date.day.unwrap_or(1) as u32)
+    }
+
+    fn get_cover(&self) -> Option<(String, Vec<u8>)> {
+        self.tag.pictures()
+            .find(|p| p.picture_type == PictureType::CoverFront)
+            .map(|p| (p.mime_type.to_string(), p.data.clone()))
+    }
+
+    fn clear(&mut self) {
+        self.tag = Tag::new();
     }
 
     fn save(&mut self) -> Result<(), SpotifyError> {
@@ -84,4 +174,3 @@ impl super::Tag for ID3Tag {
     }
 }
 
-    
\ No newline at end of file
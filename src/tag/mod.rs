@@ -1,5 +1,6 @@
 use std::path::Path;
 use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
 
 use crate::downloader::AudioFormat;
 use crate::error::SpotifyError;
@@ -30,7 +31,6 @@ impl TagWrap {
         }
     }
 
-
     /// Get Tag trait
     pub fn get_tag(&mut self) -> Box<&mut dyn Tag> {
         match self {
@@ -48,10 +48,38 @@ pub trait Tag {
     fn set_field(&mut self, field: Field, value: Vec<String>);
     fn set_release_date(&mut self, date: NaiveDate);
     fn add_cover(&mut self, mime: &str, data: Vec<u8>);
+    // Read raw values as stored
+    fn get_raw(&self, tag: &str) -> Vec<String>;
+    // Read field, multi-value fields are split by separator
+    fn get_field(&self, field: &Field) -> Vec<String>;
+    fn get_release_date(&self) -> Option<NaiveDate>;
+    // Returns mime and data
+    fn get_cover(&self) -> Option<(String, Vec<u8>)>;
+    // Remove all existing values
+    fn clear(&mut self);
     fn save(&mut self) -> Result<(), SpotifyError>;
+
+    // Wether value is already present in file
+    fn has_field(&self, field: &Field) -> bool {
+        !self.get_field(field).is_empty()
+    }
+    fn has_release_date(&self) -> bool {
+        self.get_release_date().is_some()
+    }
+    fn has_cover(&self) -> bool {
+        self.get_cover().is_some()
+    }
 }
 
-#[derive(Debug, Clone)]
+/// Split joined values of multi-value field by separator, other fields are returned whole
+fn split_values(field: &Field, values: Vec<String>, separator: &str) -> Vec<String> {
+    if separator.is_empty() || !field.is_multi_value() {
+        return values;
+    }
+    values.iter().flat_map(|v| v.split(separator).map(|v| v.to_string())).collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Field {
     Title,
     Artist,
@@ -60,5 +88,140 @@ pub enum Field {
     DiscNumber,
     AlbumArtist,
     Genre,
-    Label
-}
\ No newline at end of file
+    Label,
+    TrackTotal,
+    DiscTotal,
+    ISRC,
+    UPC,
+    Explicit,
+    Copyright,
+    Popularity,
+    TrackId,
+    AlbumId,
+    ArtistId,
+    URL,
+    AlbumURL
+}
+
+impl Field {
+    /// Field can have multiple values, joined by separator in formats without native support
+    pub fn is_multi_value(&self) -> bool {
+        matches!(self, Field::Artist | Field::AlbumArtist | Field::Genre | Field::ArtistId | Field::Copyright)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use chrono::NaiveDate;
+
+    use crate::downloader::AudioFormat;
+    use super::{Field, TagWrap};
+
+    const COVER: &[u8] = b"\xff\xd8\xff\xe0fixture cover\xff\xd9";
+
+    /// Copy fixture to temp folder, so tests don't share files
+    fn fixture(name: &str, test: &str) -> PathBuf {
+        let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("fixtures").join(name);
+        let dst = std::env::temp_dir().join(format!("spotidown-{}-{}-{}", std::process::id(), test, name));
+        std::fs::copy(src, &dst).unwrap();
+        dst
+    }
+
+    /// Write tags to fixture, read them back from fresh instance
+    fn round_trip(name: &str, format: AudioFormat, cover: bool) {
+        let path = fixture(name, "round-trip");
+        let date = NaiveDate::from_ymd(2021, 3, 14);
+        {
+            let mut wrap = TagWrap::new(&path, format.clone()).unwrap();
+            let tag = wrap.get_tag();
+            tag.set_separator(", ");
+            tag.set_field(Field::Title, vec!["Hello, World".to_string()]);
+            tag.set_field(Field::Album, vec!["Příliš žluťoučký kůň 日本語 🎵".to_string()]);
+            tag.set_field(Field::Artist, vec!["Björk".to_string(), "Sigur Rós".to_string()]);
+            tag.set_field(Field::Copyright, vec!["© 2021 Label, Inc.".to_string()]);
+            tag.set_field(Field::TrackNumber, vec!["3".to_string()]);
+            tag.set_field(Field::TrackTotal, vec!["12".to_string()]);
+            tag.set_field(Field::DiscNumber, vec!["1".to_string()]);
+            tag.set_field(Field::DiscTotal, vec!["2".to_string()]);
+            tag.set_field(Field::Explicit, vec!["1".to_string()]);
+            tag.set_field(Field::TrackId, vec!["4uLU6hMCjMI75M1A2tKUQC".to_string()]);
+            tag.set_release_date(date);
+            tag.add_cover("image/jpeg", COVER.to_vec());
+            tag.save().unwrap();
+        }
+
+        let mut wrap = TagWrap::new(&path, format).unwrap();
+        let tag = wrap.get_tag();
+        tag.set_separator(", ");
+        assert_eq!(tag.get_field(&Field::Title), vec!["Hello, World"]);
+        assert_eq!(tag.get_field(&Field::Album), vec!["Příliš žluťoučký kůň 日本語 🎵"]);
+        assert_eq!(tag.get_field(&Field::Artist), vec!["Björk", "Sigur Rós"]);
+        assert_eq!(tag.get_field(&Field::TrackNumber), vec!["3"]);
+        assert_eq!(tag.get_field(&Field::TrackTotal), vec!["12"]);
+        assert_eq!(tag.get_field(&Field::DiscNumber), vec!["1"]);
+        assert_eq!(tag.get_field(&Field::DiscTotal), vec!["2"]);
+        assert_eq!(tag.get_field(&Field::Explicit), vec!["1"]);
+        assert_eq!(tag.get_field(&Field::TrackId), vec!["4uLU6hMCjMI75M1A2tKUQC"]);
+        assert!(!tag.has_field(&Field::Genre));
+        assert_eq!(tag.get_release_date(), Some(date));
+        match cover {
+            true => assert_eq!(tag.get_cover(), Some(("image/jpeg".to_string(), COVER.to_vec()))),
+            false => assert_eq!(tag.get_cover(), None)
+        }
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn round_trip_mp3() {
+        round_trip("silence.mp3", AudioFormat::MP3, true);
+    }
+
+    #[test]
+    fn round_trip_m4a() {
+        round_trip("silence.m4a", AudioFormat::AAC, true);
+    }
+
+    #[test]
+    fn round_trip_ogg() {
+        round_trip("silence.ogg", AudioFormat::OGG, false);
+    }
+
+    #[test]
+    fn joined_values_use_separator() {
+        for (name, format, frame) in [("silence.mp3", AudioFormat::MP3, "TPE1"), ("silence.m4a", AudioFormat::AAC, "©ART")] {
+            let path = fixture(name, "separator");
+            {
+                let mut wrap = TagWrap::new(&path, format.clone()).unwrap();
+                let tag = wrap.get_tag();
+                tag.set_separator("; ");
+                tag.set_field(Field::Artist, vec!["A, B".to_string(), "C".to_string()]);
+                tag.set_field(Field::Title, vec!["One; Two".to_string()]);
+                tag.save().unwrap();
+            }
+            let mut wrap = TagWrap::new(&path, format).unwrap();
+            let tag = wrap.get_tag();
+            tag.set_separator("; ");
+            assert_eq!(tag.get_raw(frame), vec!["A, B; C"]);
+            assert_eq!(tag.get_field(&Field::Artist), vec!["A, B", "C"]);
+            assert_eq!(tag.get_field(&Field::Title), vec!["One; Two"]);
+            std::fs::remove_file(path).ok();
+        }
+    }
+
+    #[test]
+    fn ogg_multi_value_is_native() {
+        let path = fixture("silence.ogg", "native");
+        {
+            let mut wrap = TagWrap::new(&path, AudioFormat::OGG).unwrap();
+            let tag = wrap.get_tag();
+            tag.set_field(Field::Genre, vec!["Rock, Pop".to_string(), "Jazz".to_string()]);
+            tag.save().unwrap();
+        }
+        let mut wrap = TagWrap::new(&path, AudioFormat::OGG).unwrap();
+        let tag = wrap.get_tag();
+        assert_eq!(tag.get_raw("GENRE"), vec!["Rock, Pop", "Jazz"]);
+        assert_eq!(tag.get_field(&Field::Genre), vec!["Rock, Pop", "Jazz"]);
+        std::fs::remove_file(path).ok();
+    }
+}
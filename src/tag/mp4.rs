@@ -1,6 +1,6 @@
 use std::path::{Path, PathBuf};
 use std::convert::TryInto;
-use mp4ameta::{Tag, Data, Img};
+use mp4ameta::{Tag, Data, Img, ImgFmt};
 use mp4ameta::ident::DataIdent;
 use chrono::{DateTime, NaiveDate, Utc};
 
@@ -24,48 +24,137 @@ impl MP4Tag {
             separator: ", ".to_owned()
         })
     }
-}
-
-impl super::Tag for MP4Tag {
-    fn set_separator(&mut self, separator: &str) {
-        self.separator = separator.to_string();
-    }
 
-    fn set_raw(&mut self, tag: &str, value: Vec<String>) {
+    /// Get fourcc or freeform ident from raw tag name
+    fn raw_ident(tag: &str) -> DataIdent {
         let mut bytes = tag.as_bytes().to_owned();
         // Replace UTF-8 © with the proper character
         if bytes.len() == 5 && bytes[0..2] == [194, 169] {
             bytes = vec![0xa9, bytes[2], bytes[3], bytes[4]];
         }
 
-        let data: Vec<_> = value.into_iter().map(|v| Data::Utf8(v)).collect();
-
         // Fourcc
         if bytes.len() == 4 {
-            let ident = DataIdent::fourcc(bytes.try_into().unwrap());
-            self.tag.set_all_data(ident, data);
-            return;
+            return DataIdent::fourcc(bytes.try_into().unwrap());
         }
 
         // Freeform
-        let ident = DataIdent::freeform("com.apple.iTunes", tag);
-        self.tag.set_all_data(ident, data);
+        DataIdent::freeform("com.apple.iTunes", tag)
     }
 
-    fn set_field(&mut self, field: Field, value: Vec<String>) {
+    /// Read all values of atom
+    fn get_data(&self, ident: &DataIdent) -> Vec<String> {
+        self.tag.data_of(ident).filter_map(|d| match d {
+            Data::Utf8(v) => Some(v.to_string()),
+            Data::Utf16(v) => Some(v.to_string()),
+            Data::BeSigned(v) => v.first().map(|v| v.to_string()),
+            _ => None
+        }).collect()
+    }
+
+    /// Get atom for field, None for integer trkn / disk pairs
+    fn field_ident(field: &Field) -> Option<DataIdent> {
         let ident = match field {
             Field::Title => DataIdent::fourcc(*b"\xa9nam"),
             Field::Artist => DataIdent::fourcc(*b"\xa9ART"),
             Field::Album => DataIdent::fourcc(*b"\xa9alb"),
-            Field::TrackNumber => DataIdent::fourcc(*b"trkn"),
-            Field::DiscNumber => DataIdent::fourcc(*b"disk"),
             Field::AlbumArtist => DataIdent::fourcc(*b"aART"),
             Field::Genre => DataIdent::fourcc(*b"\xa9gen"),
             Field::Label => DataIdent::freeform("com.apple.iTunes", "LABEL"),
+            Field::Copyright => DataIdent::fourcc(*b"cprt"),
+            Field::Explicit => DataIdent::fourcc(*b"rtng"),
+            Field::ISRC => DataIdent::freeform("com.apple.iTunes", "ISRC"),
+            Field::UPC => DataIdent::freeform("com.apple.iTunes", "BARCODE"),
+            Field::Popularity => DataIdent::freeform("com.apple.iTunes", "SPOTIFY_POPULARITY"),
+            Field::TrackId => DataIdent::freeform("com.apple.iTunes", "SPOTIFY_TRACK_ID"),
+            Field::AlbumId => DataIdent::freeform("com.apple.iTunes", "SPOTIFY_ALBUM_ID"),
+            Field::ArtistId => DataIdent::freeform("com.apple.iTunes", "SPOTIFY_ARTIST_ID"),
+            Field::URL => DataIdent::freeform("com.apple.iTunes", "URL"),
+            Field::AlbumURL => DataIdent::freeform("com.apple.iTunes", "SPOTIFY_ALBUM_URL"),
+            Field::TrackNumber | Field::TrackTotal | Field::DiscNumber | Field::DiscTotal => return None
+        };
+        Some(ident)
+    }
+}
+
+impl super::Tag for MP4Tag {
+    fn set_separator(&mut self, separator: &str) {
+        self.separator = separator.to_string();
+    }
+
+    fn set_raw(&mut self, tag: &str, value: Vec<String>) {
+        let data: Vec<_> = value.into_iter().map(|v| Data::Utf8(v)).collect();
+        self.tag.set_all_data(MP4Tag::raw_ident(tag), data);
+    }
+
+    fn set_field(&mut self, field: Field, value: Vec<String>) {
+        let ident = match MP4Tag::field_ident(&field) {
+            Some(ident) => ident,
+            None => {
+                // Integer trkn / disk atoms
+                let n = match value.first().and_then(|v| v.parse().ok()) {
+                    Some(n) => n,
+                    None => return
+                };
+                match field {
+                    Field::TrackNumber => self.tag.set_track_number(n),
+                    Field::TrackTotal => self.tag.set_total_tracks(n),
+                    Field::DiscNumber => self.tag.set_disc_number(n),
+                    _ => self.tag.set_total_discs(n)
+                }
+                return;
+            }
         };
+
+        // 1 = explicit, 0 = none
+        if let Field::Explicit = field {
+            let explicit = value.first().map(|v| v == "1").unwrap_or(false);
+            self.tag.set_data(ident, Data::BeSigned(vec![explicit as u8]));
+            return;
+        }
         self.tag.set_data(ident, Data::Utf8(value.join(&self.separator)));
     }
 
+    fn get_raw(&self, tag: &str) -> Vec<String> {
+        self.get_data(&MP4Tag::raw_ident(tag))
+    }
+
+    fn get_field(&self, field: &Field) -> Vec<String> {
+        if let Some(ident) = MP4Tag::field_ident(field) {
+            return super::split_values(field, self.get_data(&ident), &self.separator);
+        }
+        let n = match field {
+            Field::TrackNumber => self.tag.track_number(),
+            Field::TrackTotal => self.tag.total_tracks(),
+            Field::DiscNumber => self.tag.disc_number(),
+            _ => self.tag.total_discs()
+        };
+        n.map(|n| vec![n.to_string()]).unwrap_or_default()
+    }
+
+    fn get_release_date(&self) -> Option<NaiveDate> {
+        // ISO timestamp, only date part is used
+        let date = self.tag.strings_of(&DataIdent::fourcc(*b"\xa9day")).next()?;
+        NaiveDate::parse_from_str(date.get(0..10)?, "%Y-%m-%d").ok()
+    }
+
+    fn get_cover(&self) -> Option<(String, Vec<u8>)> {
+        let img = self.tag.artwork()?;
+        let mime = match img.fmt {
+            ImgFmt::Jpeg => "image/jpeg",
+            ImgFmt::Png => "image/png",
+            ImgFmt::Bmp => "image/bmp"
+        };
+        Some((mime.to_string(), img.data.to_vec()))
+    }
+
+    fn clear(&mut self) {
+        let idents: Vec<DataIdent> = self.tag.data().map(|(ident, _)| ident.clone()).collect();
+        for ident in idents {
+            self.tag.remove_data_of(&ident);
+        }
+    }
+
     fn set_release_date(&mut self, date: NaiveDate) {
         let ident = DataIdent::fourcc(*b"\xa9day");
         // Convert NaiveDate to ISO timestamp
@@ -77,8 +166,8 @@ impl super::Tag for MP4Tag {
     fn add_cover(&mut self, mime: &str, data: Vec<u8>) {
         let mime = mime.trim();
         match mime {
-            "image/jpeg" | "image/jpg" => self.tag.add_artwork(Img::jpeg(data)),
-            "image/png" => self.tag.add_artwork(Img::png(data)),
+            "image/jpeg" | "image/jpg" => self.tag.set_artwork(Img::jpeg(data)),
+            "image/png" => self.tag.set_artwork(Img::png(data)),
             _ => warn!("Invalid mime: {}, skipping album art!", mime)
         };
     }
@@ -16,19 +16,16 @@ impl OGGTag {
     pub fn open(path: impl AsRef<Path>) -> Result<OGGTag, SpotifyError> {
         let mut file = File::open(&path)?;
         let tag = read_comment_header(&mut file);
-    
+
         Ok(OGGTag {
             path: path.as_ref().to_owned(),
             tag
         })
     }
-}
-
-impl super::Tag for OGGTag {
-    fn set_separator(&mut self, _separator: &str) {}
 
-    fn set_field(&mut self, field: Field, value: Vec<String>) {
-        let tag = match field {
+    /// Get vorbis comment name for field
+    fn field_name(field: &Field) -> &'static str {
+        match field {
             Field::Title => "TITLE",
             Field::Artist => "ARTIST",
             Field::Album => "ALBUM",
@@ -37,8 +34,51 @@ impl super::Tag for OGGTag {
             Field::Genre => "GENRE",
             Field::Label => "LABEL",
             Field::AlbumArtist => "ALBUMARTIST",
-        };
-        self.set_raw(tag, value);
+            Field::TrackTotal => "TRACKTOTAL",
+            Field::DiscTotal => "DISCTOTAL",
+            Field::ISRC => "ISRC",
+            Field::UPC => "BARCODE",
+            Field::Explicit => "ITUNESADVISORY",
+            Field::Copyright => "COPYRIGHT",
+            Field::Popularity => "SPOTIFY_POPULARITY",
+            Field::TrackId => "SPOTIFY_TRACK_ID",
+            Field::AlbumId => "SPOTIFY_ALBUM_ID",
+            Field::ArtistId => "SPOTIFY_ARTIST_ID",
+            Field::URL => "WWWAUDIOFILE",
+            Field::AlbumURL => "SPOTIFY_ALBUM_URL",
+        }
+    }
+}
+
+impl super::Tag for OGGTag {
+    fn set_separator(&mut self, _separator: &str) {}
+
+    fn set_field(&mut self, field: Field, value: Vec<String>) {
+        self.set_raw(OGGTag::field_name(&field), value);
+    }
+
+    fn get_raw(&self, tag: &str) -> Vec<String> {
+        self.tag.get_tag_multi(tag)
+    }
+
+    fn get_field(&self, field: &Field) -> Vec<String> {
+        self.get_raw(OGGTag::field_name(field))
+    }
+
+    fn get_release_date(&self) -> Option<NaiveDate> {
+        let date = self.tag.get_tag_single("DATE")?;
+        NaiveDate::parse_from_str(date.get(0..10)?, "%Y-%m-%d").ok()
+    }
+
+    // Covers aren't supported
+    fn get_cover(&self) -> Option<(String, Vec<u8>)> {
+        None
+    }
+
+    fn clear(&mut self) {
+        let vendor = self.tag.get_vendor();
+        self.tag = CommentHeader::new();
+        self.tag.set_vendor(&vendor);
     }
 
     fn add_cover(&mut self, _mime: &str, _data: Vec<u8>) {
@@ -46,6 +86,8 @@ impl super::Tag for OGGTag {
     }
 
     fn set_raw(&mut self, tag: &str, value: Vec<String>) {
+        // Vorbis comments can repeat, remove old values
+        self.tag.clear_tag(tag);
         self.tag.add_tag_multi(tag, &value.iter().map(|v| v.as_str()).collect::<Vec<&str>>());
     }
 
@@ -58,6 +100,7 @@ impl super::Tag for OGGTag {
     }
 
     fn set_release_date(&mut self, date: NaiveDate) {
+        self.tag.clear_tag("DATE");
         self.tag.add_tag_single("DATE", &format!("{}-{:02}-{:02}", date.year(), date.month(), date.day()))
     }
-}
\ No newline at end of file
+}
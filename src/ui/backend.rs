@@ -1,50 +1,173 @@
 use std::path::PathBuf;
+use std::hash::{Hash, Hasher};
+use std::time::Duration;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use futures::stream::BoxStream;
 use async_std::channel::{Receiver, Sender, bounded};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::AsyncWriteExt;
 use tokio::fs::File;
 use tokio::task;
+use tokio::sync::broadcast::error::RecvError;
 use serde::{Serialize, Deserialize};
+use serde_json::{Value, json};
+use librespot::core::authentication::Credentials;
 
-use crate::downloader::{Download, Downloader, DownloaderConfig};
+use crate::config::Overrides;
+use crate::downloader::{Download, DownloadOverrides, Downloader, DownloaderConfig, QueueMove};
+use super::Message;
 use crate::error::SpotifyError;
-use crate::spotify::Spotify;
+use crate::logger;
+use crate::spotify::{AccountType, Connection, ConnectionState, RateLimit, Spotify};
+use crate::secrets::{SecretStore, Secrets};
+use crate::web_api::ApiUrls;
+use crate::proxy::ProxyConfig;
 
 
+/// Unique backend instance IDs
+static BACKEND_ID: AtomicUsize = AtomicUsize::new(0);
+
 #[derive(Debug, Clone)]
 pub struct Backend {
     tx: Sender<BackendMessage>,
-    rx: Receiver<BackendResponse>
+    rx: Receiver<BackendResponse>,
+    rate_limit: RateLimit,
+    connection: Connection,
+    downloader: Downloader,
+    // Restarts event subscription when account changes
+    id: usize,
+    // Reloads encrypted secrets on account switch and logout
+    passphrase: Option<String>,
+    /// Usernames of all accounts
+    pub accounts: Vec<String>,
+    /// Username of active account
+    pub active: String,
+    pub account_type: AccountType
 }
 
 impl Backend {
-    async fn new(settings: Settings, spotify: Spotify) -> Backend {
-        let downloader = Downloader::new(settings.downloader.clone(), spotify.clone());
+    async fn new(settings: Settings, spotify: Spotify, passphrase: Option<String>) -> Backend {
+        logger::set_level(settings.log_level.as_deref());
+        let downloader = Downloader::new(settings.downloader_config(), spotify.clone());
+        // Connect other accounts to download pool in background
+        let others: Vec<Account> = settings.accounts.iter().filter(|a| Some(&a.username) != settings.active.as_ref()).cloned().collect();
+        let (urls, proxy, default_config, pool) = (settings.api_urls.clone(), settings.proxy.clone(), settings.downloader.clone(), downloader.clone());
+        let pool_passphrase = passphrase.clone();
+        task::spawn(async move {
+            for account in others {
+                match account.get_pool_spotify(urls.clone(), proxy.clone(), pool_passphrase.as_deref()).await {
+                    Ok(spotify) => {
+                        let limit = account.downloader.as_ref().unwrap_or(&default_config).concurrent_downloads;
+                        pool.add_account(&account.username, Arc::new(spotify), limit);
+                    }
+                    Err(e) => warn!("Failed connecting account {} to download pool: {}", account.username, e)
+                }
+            }
+        });
         let (tx, rx_0) = bounded(1);
         let (tx_1, rx) = bounded(1);
+        let rate_limit = spotify.metadata.rate_limit.clone();
+        let connection = spotify.connection.clone();
+        let account_type = spotify.account_type;
+        let accounts = settings.accounts.iter().map(|a| a.username.to_string()).collect();
+        let active = settings.active.clone().unwrap_or_default();
+        let downloader_clone = downloader.clone();
         task::spawn(async move {
-            worker_thread(settings, spotify, downloader, rx_0, tx_1).await;
+            worker_thread(settings, spotify, downloader_clone, rx_0, tx_1).await;
         });
-        Backend { tx, rx }
+        Backend { 
+            tx, rx, rate_limit, connection, downloader, accounts, active, account_type, passphrase,
+            id: BACKEND_ID.fetch_add(1, Ordering::Relaxed)
+        }
+    }
+
+    /// Try loading saved settings on startup, passphrase is needed for encrypted secrets.
+    /// Account from environment or command line is used only here, so switching and logging out stick
+    pub async fn try_load(passphrase: Option<String>) -> Result<Backend, SpotifyError> {
+        // Credentials from environment or command line
+        let overrides = Overrides::from_env()?;
+        let passphrase = passphrase.or(overrides.get("passphrase").map(|(p, _)| p.to_string()));
+        if let Some((username, password, client_id, client_secret)) = overrides.credentials() {
+            // Known account uses stored reusable credentials, password login only once
+            let known = Settings::load().await.ok().filter(|s| s.find_account(&username).is_some());
+            if let Some(mut settings) = known {
+                settings.select_override_account();
+                match settings.get_spotify(passphrase.as_deref()).await {
+                    Ok(spotify) => return Ok(Backend::new(settings, spotify, passphrase).await),
+                    // Don't replace encrypted secrets
+                    Err(SpotifyError::Locked) => return Err(SpotifyError::Locked),
+                    Err(e) => warn!("Stored credentials of {} failed, logging in with password: {}", username, e)
+                }
+            }
+            return Backend::login(username, password, client_id, client_secret, passphrase.unwrap_or_default()).await;
+        }
+        let mut settings = Settings::load().await?;
+        settings.select_override_account();
+        let spotify = settings.get_spotify(passphrase.as_deref()).await?;
+        Ok(Backend::new(settings, spotify, passphrase).await)
+    }
+
+    /// Load active account of saved settings, overrides of startup account are ignored
+    async fn reload(passphrase: Option<String>) -> Result<Backend, SpotifyError> {
+        let mut settings = Settings::load().await?;
+        let spotify = settings.get_spotify(passphrase.as_deref()).await?;
+        Ok(Backend::new(settings, spotify, passphrase).await)
+    }
+
+    /// Login, add account and save settings, secrets are encrypted if passphrase isn't empty
+    pub async fn login(username: String, password: String, client_id: String, client_secret: String, passphrase: String) -> Result<Backend, SpotifyError> {
+        let mut settings = match Settings::load().await {
+            Ok(settings) => settings,
+            Err(_) => Settings::new().ok_or(SpotifyError::Error("Failed creating settings!".into()))?
+        };
+        let credentials = Credentials::with_password(&username, &password);
+        let spotify = Spotify::new(credentials, &client_id, &client_secret, settings.api_urls.clone(), settings.proxy.clone()).await?;
+        let secret_store = Secrets::new(spotify.credentials.clone(), &client_secret).save(Some(&passphrase))?;
+        let mut account = Account::new(&spotify.credentials.username, &client_id, secret_store);
+        // Logged in with email, remember it to find account next time
+        if username != account.username {
+            account.login_name = Some(username);
+        }
+        settings.add_account(account);
+        settings.save().await?;
+        Ok(Backend::new(settings, spotify, Some(passphrase).filter(|p| !p.is_empty())).await)
     }
 
-    /// Try loading saved settings
-    pub async fn try_load() -> Result<Backend, SpotifyError> {
-        let settings = Settings::load().await?;
-        let spotify = settings.get_spotify().await?;
-        Ok(Backend::new(settings, spotify).await)
+    /// Stop downloader and worker thread
+    pub async fn shutdown(self) {
+        self.tx.send(BackendMessage::Shutdown).await.ok();
+        self.rx.recv().await.ok();
     }
 
-    /// Login and save settings
-    pub async fn login(username: String, password: String, client_id: String, client_secret: String) -> Result<Backend, SpotifyError> {
-        let spotify = Spotify::new(&username, &password, &client_id, &client_secret).await?;
-        let settings = Settings::new(&username, &password, &client_id, &client_secret).ok_or(SpotifyError::Error("Failed creating settings!".into()))?;
+    /// Shut down and reload with different account
+    pub async fn switch_account(self, username: String) -> Result<Backend, SpotifyError> {
+        let passphrase = self.passphrase.clone();
+        self.shutdown().await;
+        let mut settings = Settings::load().await?;
+        settings.active = Some(username);
         settings.save().await?;
-        Ok(Backend::new(settings, spotify).await)
+        Backend::reload(passphrase).await
+    }
+
+    /// Remove active account and reload with next one
+    pub async fn logout(self) -> Result<Backend, SpotifyError> {
+        let username = self.active.clone();
+        let passphrase = self.passphrase.clone();
+        self.shutdown().await;
+        let mut settings = Settings::load().await?;
+        settings.remove_account(&username);
+        settings.save().await?;
+        Backend::reload(passphrase).await
     }
 
     /// Add URI to queue
-    pub async fn add_uri(self, uri: String) {
-        self.tx.send(BackendMessage::AddUri(uri)).await.ok();
+    pub async fn add_uri(self, uri: String, overrides: Option<DownloadOverrides>) {
+        self.tx.send(BackendMessage::AddUri(uri, overrides)).await.ok();
+    }
+
+    /// Move pending download in queue
+    pub async fn move_download(self, id: i64, direction: QueueMove) {
+        self.tx.send(BackendMessage::Move(id, direction)).await.ok();
     }
 
     /// Get all downloads
@@ -56,17 +179,41 @@ impl Backend {
         unreachable!();
     }
 
-    /// Get config
-    pub async fn get_config(self) -> DownloaderConfig {
+    /// Get remaining Web API rate limit cooldown
+    pub fn rate_limit(&self) -> Option<Duration> {
+        self.rate_limit.remaining()
+    }
+
+    /// Get session state of active account
+    pub fn connection(&self) -> ConnectionState {
+        self.connection.state()
+    }
+
+    /// Subscription to downloader queue events
+    pub fn events(&self) -> iced::Subscription<Message> {
+        iced::Subscription::from_recipe(DownloaderEvents {
+            downloader: self.downloader.clone(),
+            backend_id: self.id
+        })
+    }
+
+    /// Get config, and whether it is account specific
+    pub async fn get_config(self) -> (DownloaderConfig, bool) {
         self.tx.send(BackendMessage::GetConfig).await.ok();
-        if let BackendResponse::Config(d) = self.rx.recv().await.unwrap() {
-            return d;
+        if let BackendResponse::Config(d, per_account) = self.rx.recv().await.unwrap() {
+            return (d, per_account);
         }
         unreachable!();
     }
 
-    pub async fn save_config(self, config: DownloaderConfig) {
-        self.tx.send(BackendMessage::SetConfig(config)).await.ok();
+    /// Export failed downloads report to download folder
+    pub async fn export_errors(self) {
+        self.tx.send(BackendMessage::ExportErrors).await.ok();
+    }
+
+    /// Save config as default or only for active account
+    pub async fn save_config(self, config: DownloaderConfig, per_account: bool) {
+        self.tx.send(BackendMessage::SetConfig(config, per_account)).await.ok();
     }
 }
 
@@ -74,67 +221,256 @@ impl Backend {
 async fn worker_thread(mut settings: Settings, _spotify: Spotify, mut downloader: Downloader, rx: Receiver<BackendMessage>, tx: Sender<BackendResponse>) {
     while let Ok(msg) = rx.recv().await {
         match msg {
-            BackendMessage::AddUri(uri) => {
-                downloader.add_uri(&uri).await.ok();
+            BackendMessage::AddUri(uri, overrides) => {
+                downloader.add_uri_with(&uri, overrides).await.ok();
+            }
+            BackendMessage::Move(id, direction) => {
+                downloader.move_download(id, direction).await;
             }
             BackendMessage::GetDownloads => {
                 tx.send(BackendResponse::Downloads(downloader.get_downloads().await)).await.ok();
             }
             BackendMessage::GetConfig => {
-                tx.send(BackendResponse::Config(settings.downloader.clone())).await.ok();
+                let per_account = settings.active_account().map(|a| a.downloader.is_some()).unwrap_or(false);
+                tx.send(BackendResponse::Config(settings.downloader_config(), per_account)).await.ok();
             }
-            BackendMessage::SetConfig(c) => {
+            BackendMessage::SetConfig(c, per_account) => {
                 downloader.set_config(c.clone()).await;
-                settings.downloader = c;
+                if let Some(active) = &settings.active {
+                    downloader.set_account_limit(active, c.concurrent_downloads);
+                }
+                // Don't persist environment and command line values
+                let c = settings.overrides.unapply(&c, &settings.file_config()).unwrap_or(c);
+                if let Some(account) = settings.active_account_mut() {
+                    account.downloader = match per_account {
+                        true => Some(c.clone()),
+                        false => None
+                    };
+                }
+                if !per_account {
+                    settings.downloader = c;
+                }
                 settings.save().await.ok();
             }
+            BackendMessage::ExportErrors => {
+                let path = settings.downloader_config().path.join("spotidown-errors.json");
+                match downloader.export_errors(&path).await {
+                    Ok(_) => info!("Exported errors to: {:?}", path),
+                    Err(e) => error!("Failed exporting errors: {}", e)
+                }
+            }
+            BackendMessage::Shutdown => {
+                downloader.shutdown().await;
+                tx.send(BackendResponse::Shutdown).await.ok();
+                break;
+            }
             
         }
     }
 }
 
 
+/// iced recipe streaming downloader events
+struct DownloaderEvents {
+    downloader: Downloader,
+    backend_id: usize
+}
+
+impl<H: Hasher, I> iced_native::subscription::Recipe<H, I> for DownloaderEvents {
+    type Output = Message;
+
+    fn hash(&self, state: &mut H) {
+        std::any::TypeId::of::<Self>().hash(state);
+        self.backend_id.hash(state);
+    }
+
+    fn stream(self: Box<Self>, _input: BoxStream<'static, I>) -> BoxStream<'static, Message> {
+        let rx = self.downloader.subscribe();
+        Box::pin(futures::stream::unfold(rx, |mut rx| async move {
+            match rx.recv().await {
+                Ok(event) => Some((Message::DownloaderEvent(event), rx)),
+                // Missed events, resync whole queue
+                Err(RecvError::Lagged(_)) => Some((Message::GetDownloadQueue, rx)),
+                Err(RecvError::Closed) => None
+            }
+        }))
+    }
+}
+
 enum BackendMessage {
-    AddUri(String),
+    AddUri(String, Option<DownloadOverrides>),
+    Move(i64, QueueMove),
     GetDownloads,
     GetConfig,
-    SetConfig(DownloaderConfig)
+    SetConfig(DownloaderConfig, bool),
+    ExportErrors,
+    Shutdown
 }
 
 enum BackendResponse {
     Downloads(Vec<Download>),
-    Config(DownloaderConfig)
+    Config(DownloaderConfig, bool),
+    Shutdown
 }
 
 
+/// Current settings schema version
+const SETTINGS_VERSION: usize = 1;
+/// Settings migrations, index is version migrated from
+const MIGRATIONS: [fn(&mut Value) -> Result<(), SpotifyError>; SETTINGS_VERSION] = [migrate_v0];
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Settings {
-    pub username: String,
-    pub password: String,
-    pub client_id: String,
-    pub client_secret: String,
-    pub downloader: DownloaderConfig
+    /// Schema version, missing in files from older versions
+    #[serde(default)]
+    pub version: usize,
+    /// Account profiles
+    #[serde(default)]
+    pub accounts: Vec<Account>,
+    /// Username of active account
+    #[serde(default)]
+    pub active: Option<String>,
+    /// Default downloader config, accounts can override it
+    #[serde(default = "DownloaderConfig::fallback")]
+    pub downloader: DownloaderConfig,
+    /// Web API endpoints
+    #[serde(default)]
+    pub api_urls: ApiUrls,
+    /// Proxy for all Spotify traffic and cover downloads
+    #[serde(default)]
+    pub proxy: Option<ProxyConfig>,
+    /// Log level (error, warn, info, debug, trace), SPOTIDOWN_LOG env var takes precedence
+    #[serde(default)]
+    pub log_level: Option<String>,
+    /// Environment and command line values
+    #[serde(skip)]
+    pub overrides: Overrides
 }
 
 impl Settings {
-    /// Create new instance with authentication
-    pub fn new(username: &str, password: &str, client_id: &str, client_secret: &str) -> Option<Settings> {
+    /// Create new instance without accounts
+    pub fn new() -> Option<Settings> {
         Some(Settings {
-            username: username.to_string(),
-            password: password.to_string(),
-            client_id: client_id.to_string(),
-            client_secret: client_secret.to_string(),
-            downloader: DownloaderConfig::default()?
+            version: SETTINGS_VERSION,
+            accounts: vec![],
+            active: None,
+            downloader: DownloaderConfig::default()?,
+            api_urls: ApiUrls::default(),
+            proxy: None,
+            log_level: None,
+            overrides: Overrides::from_env().unwrap_or_default()
         })
     }
 
-    /// Get spotify client
-    pub async fn get_spotify(&self) -> Result<Spotify, SpotifyError> {
-        Spotify::new(&self.username, &self.password, &self.client_id, &self.client_secret).await
+    /// Get active account
+    pub fn active_account(&self) -> Option<&Account> {
+        let active = self.active.as_ref()?;
+        self.accounts.iter().find(|a| &a.username == active)
+    }
+
+    /// Get active account mutably
+    pub fn active_account_mut(&mut self) -> Option<&mut Account> {
+        let active = self.active.clone()?;
+        self.accounts.iter_mut().find(|a| a.username == active)
+    }
+
+    /// Add or replace account and make it active
+    pub fn add_account(&mut self, account: Account) {
+        self.active = Some(account.username.to_string());
+        match self.accounts.iter_mut().find(|a| a.username == account.username) {
+            // Keep account settings
+            Some(a) => {
+                a.client_id = account.client_id;
+                a.secret_store = account.secret_store;
+                a.login_name = account.login_name.or(a.login_name.take());
+            }
+            None => self.accounts.push(account)
+        }
+    }
+
+    /// Canonical username of account logged in with username or email
+    pub fn find_account(&self, name: &str) -> Option<String> {
+        self.accounts.iter()
+            .find(|a| a.username == name || a.login_name.as_deref() == Some(name))
+            .map(|a| a.username.to_string())
+    }
+
+    /// Make account selected on command line or environment active, only on startup so switching accounts sticks
+    pub fn select_override_account(&mut self) {
+        if let Some(username) = self.overrides.get("username").and_then(|(u, _)| self.find_account(u)) {
+            self.active = Some(username);
+        }
+    }
+
+    /// Remove account and its secrets, next account becomes active
+    pub fn remove_account(&mut self, username: &str) {
+        if let Some(account) = self.accounts.iter().find(|a| a.username == username) {
+            Secrets::delete(account.secret_store, username);
+        }
+        self.accounts.retain(|a| a.username != username);
+        if self.active.as_deref() == Some(username) {
+            self.active = self.accounts.first().map(|a| a.username.to_string());
+        }
+    }
+
+    /// Get downloader config of active account
+    pub fn downloader_config(&self) -> DownloaderConfig {
+        let config = self.file_config();
+        match self.overrides.apply(&config) {
+            Ok(config) => config,
+            Err(e) => {
+                warn!("Ignoring config overrides: {}", e);
+                config
+            }
+        }
     }
 
-    /// Get config path
+    /// Downloader config of active account without overrides
+    fn file_config(&self) -> DownloaderConfig {
+        self.active_account()
+            .and_then(|a| a.downloader.clone())
+            .unwrap_or(self.downloader.clone())
+    }
+
+    /// Effective config with layer of each value
+    pub async fn describe() -> Result<String, SpotifyError> {
+        let mut settings = match Settings::load().await {
+            Ok(settings) => settings,
+            Err(_) => Settings::new().ok_or(SpotifyError::Error("Failed creating settings!".into()))?
+        };
+        settings.select_override_account();
+        // Raw file to tell which values are set
+        let path = Settings::get_path().ok_or(SpotifyError::Error("No settings folder!".into()))?;
+        let raw: Value = tokio::fs::read(&path).await.ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or(Value::Null);
+        let account = raw["accounts"].as_array()
+            .and_then(|accounts| accounts.iter().find(|a| a["username"].as_str() == settings.active.as_deref()));
+        let file_config = match account.map(|a| &a["downloader"]) {
+            Some(config) if config.is_object() => config,
+            _ => &raw["downloader"]
+        };
+        settings.overrides.describe(&settings.file_config(), Some(file_config), account)
+    }
+
+    /// Get spotify client of active account
+    pub async fn get_spotify(&mut self, passphrase: Option<&str>) -> Result<Spotify, SpotifyError> {
+        let (urls, proxy) = (self.api_urls.clone(), self.proxy.clone());
+        let account = self.active_account_mut().ok_or(SpotifyError::Error("No active account!".into()))?;
+        let username = account.username.to_string();
+        let spotify = account.get_spotify(urls, proxy, passphrase).await?;
+        if account.username != username {
+            self.active = Some(account.username.to_string());
+            self.save().await?;
+        }
+        Ok(spotify)
+    }
+
+    /// Get config path, tests use temporary folder
     pub fn get_folder() -> Option<PathBuf> {
+        if cfg!(test) {
+            return Some(std::env::temp_dir().join(format!("spotidown-test-{}", std::process::id())));
+        }
         Some(dirs::config_dir()?.join("spotidown"))
     }
 
@@ -154,12 +490,174 @@ impl Settings {
         Ok(())
     }
 
-    /// Load settings from file
+    /// Load settings from file, migrates and backs up files from older versions
     pub async fn load() -> Result<Settings, SpotifyError> {
         let path = Settings::get_path().ok_or(SpotifyError::Error("No settings folder!".into()))?;
-        let mut file = File::open(path).await?;
-        let mut buf = String::new();
-        file.read_to_string(&mut buf).await?;
-        Ok(serde_json::from_str(&buf)?)
+        let data = tokio::fs::read(&path).await?;
+        let mut value: Value = match serde_json::from_slice(&data) {
+            Ok(value) => value,
+            Err(e) => {
+                // Keep broken file, it gets overwritten on login
+                error!("Invalid settings file: {}", e);
+                Settings::backup(&path, "broken", &data)?;
+                return Err(e.into());
+            }
+        };
+
+        let version = value.get("version").and_then(|v| v.as_u64()).unwrap_or(0) as usize;
+        if version > SETTINGS_VERSION {
+            warn!("Settings are from newer version {}, unknown fields will be lost", version);
+            Settings::backup(&path, &format!("v{}", version), &data)?;
+        }
+        let original = value.clone();
+        for (i, migrate) in MIGRATIONS.iter().enumerate().skip(version) {
+            info!("Migrating settings from version {} to {}", i, i + 1);
+            migrate(&mut value)?;
+        }
+        // Secrets have to be stored before file without them is written
+        migrate_secrets(&mut value)?;
+        let mut settings: Settings = serde_json::from_value(value)?;
+        settings.overrides = Overrides::from_env()?;
+        let mut changed = false;
+        if let Some(proxy) = settings.proxy.as_mut() {
+            changed = proxy.secure_password()?;
+        }
+        if version < SETTINGS_VERSION {
+            Settings::backup(&path, &format!("v{}", version), &serde_json::to_vec_pretty(&scrub_secrets(original))?)?;
+            settings.version = SETTINGS_VERSION;
+            changed = true;
+        }
+        if changed {
+            settings.save().await?;
+        }
+        Ok(settings)
+    }
+
+    /// Write settings backup next to original, readable only by user
+    fn backup(path: &PathBuf, suffix: &str, data: &[u8]) -> Result<(), SpotifyError> {
+        let backup = path.with_extension(format!("json.{}.bak", suffix));
+        Secrets::write_private(&backup, data)?;
+        info!("Settings backed up to: {:?}", backup);
+        Ok(())
+    }
+}
+
+/// Single account settings to account profiles
+fn migrate_v0(value: &mut Value) -> Result<(), SpotifyError> {
+    let settings = value.as_object_mut().ok_or(SpotifyError::Error("Invalid settings file!".into()))?;
+    let (username, client_id) = match (settings.remove("username"), settings.remove("client_id")) {
+        (Some(Value::String(username)), Some(client_id)) => (username, client_id),
+        _ => return Ok(())
+    };
+    // Plaintext secrets are moved to secret store by migrate_secrets
+    let account = json!({
+        "username": username,
+        "client_id": client_id,
+        "secret_store": settings.remove("secret_store").unwrap_or(json!(SecretStore::default())),
+        "password": settings.remove("password"),
+        "client_secret": settings.remove("client_secret")
+    });
+    let accounts = settings.entry("accounts").or_insert(json!([]));
+    if let Some(accounts) = accounts.as_array_mut() {
+        accounts.retain(|a| a.get("username").and_then(|u| u.as_str()) != Some(username.as_str()));
+        accounts.push(account);
+    }
+    settings.insert("active".to_string(), json!(username));
+    Ok(())
+}
+
+/// Move plaintext secrets of accounts to secret store, password is replaced by reusable credentials on first login
+fn migrate_secrets(value: &mut Value) -> Result<(), SpotifyError> {
+    let accounts = match value.get_mut("accounts").and_then(|a| a.as_array_mut()) {
+        Some(accounts) => accounts,
+        None => return Ok(())
+    };
+    for account in accounts.iter_mut().filter_map(|a| a.as_object_mut()) {
+        let (password, client_secret) = match (account.remove("password"), account.remove("client_secret")) {
+            (Some(Value::String(password)), Some(Value::String(client_secret))) => (password, client_secret),
+            _ => continue
+        };
+        let username = account.get("username").and_then(|u| u.as_str()).ok_or(SpotifyError::Error("Invalid settings file!".into()))?;
+        info!("Migrating plaintext credentials of {}", username);
+        let store = Secrets::new(Credentials::with_password(username, &password), &client_secret).save(None)?;
+        account.insert("secret_store".to_string(), json!(store));
+    }
+    Ok(())
+}
+
+/// Remove plaintext secrets from settings of older versions
+fn scrub_secrets(mut value: Value) -> Value {
+    if let Some(settings) = value.as_object_mut() {
+        settings.remove("password");
+        settings.remove("client_secret");
+        if let Some(proxy) = settings.get_mut("proxy").and_then(|p| p.as_object_mut()) {
+            proxy.remove("password");
+        }
+        if let Some(accounts) = settings.get_mut("accounts").and_then(|a| a.as_array_mut()) {
+            for account in accounts.iter_mut().filter_map(|a| a.as_object_mut()) {
+                account.remove("password");
+                account.remove("client_secret");
+            }
+        }
+    }
+    value
+}
+
+/// Account profile, secrets are stored separately
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Account {
+    pub username: String,
+    pub client_id: String,
+    /// Where reusable credentials and client secret are stored
+    #[serde(default)]
+    pub secret_store: SecretStore,
+    /// Overrides default downloader config
+    #[serde(default)]
+    pub downloader: Option<DownloaderConfig>,
+    /// Email or username used to log in, if different from canonical username
+    #[serde(default)]
+    pub login_name: Option<String>
+}
+
+impl Account {
+    /// Create new instance
+    pub fn new(username: &str, client_id: &str, secret_store: SecretStore) -> Account {
+        Account {
+            username: username.to_string(),
+            client_id: client_id.to_string(),
+            secret_store,
+            downloader: None,
+            login_name: None
+        }
+    }
+
+    /// Get spotify client, replaces stored secrets with reusable credentials
+    pub async fn get_spotify(&mut self, urls: ApiUrls, proxy: Option<ProxyConfig>, passphrase: Option<&str>) -> Result<Spotify, SpotifyError> {
+        let (spotify, client_secret) = self.connect(urls, proxy, passphrase).await?;
+        // Reusable credentials can change on each login
+        Secrets::new(spotify.credentials.clone(), &client_secret).save_to(self.secret_store, passphrase)?;
+        // Secrets are stored under canonical username, migrated accounts might use email
+        if spotify.credentials.username != self.username {
+            Secrets::delete(self.secret_store, &self.username);
+            self.username = spotify.credentials.username.to_string();
+        }
+        Ok(spotify)
+    }
+
+    /// Get spotify client for download pool, account isn't renamed because settings aren't saved
+    pub async fn get_pool_spotify(&self, urls: ApiUrls, proxy: Option<ProxyConfig>, passphrase: Option<&str>) -> Result<Spotify, SpotifyError> {
+        let (spotify, client_secret) = self.connect(urls, proxy, passphrase).await?;
+        // Secrets stored under other name are replaced once account is used for login
+        if spotify.credentials.username == self.username {
+            Secrets::new(spotify.credentials.clone(), &client_secret).save_to(self.secret_store, passphrase)?;
+        }
+        Ok(spotify)
+    }
+
+    /// Login with stored secrets, returns client and client secret
+    async fn connect(&self, urls: ApiUrls, proxy: Option<ProxyConfig>, passphrase: Option<&str>) -> Result<(Spotify, String), SpotifyError> {
+        let secrets = Secrets::load(self.secret_store, &self.username, passphrase)?;
+        let spotify = Spotify::new(secrets.credentials, &self.client_id, &secrets.client_secret, urls, proxy).await?;
+        Ok((spotify, secrets.client_secret))
     }
 }
\ No newline at end of file
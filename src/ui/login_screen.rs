@@ -1,6 +1,7 @@
 use iced::{Align, Button, Column, Element, Text, TextInput, button, text_input};
 
 use super::{Message, Theme};
+use super::theme::DarkButton;
 
 #[derive(Debug, Clone, Default)]
 pub struct LoginScreen {
@@ -9,11 +10,18 @@ pub struct LoginScreen {
     pub password: String,
     pub client_id: String,
     pub client_secret: String,
+    pub passphrase: String,
+    /// Only ask for passphrase of saved secrets
+    pub unlock: bool,
+    /// Adding account while other accounts exist
+    pub can_cancel: bool,
     username_state: text_input::State,
     password_state: text_input::State,
     client_id_state: text_input::State,
     client_secret_state: text_input::State,
+    passphrase_state: text_input::State,
     login_button: button::State,
+    cancel_button: button::State,
 }
 
 impl LoginScreen {
@@ -25,9 +33,57 @@ impl LoginScreen {
         }
     }
 
+    /// Create new instance for adding another account
+    pub fn add_account(theme: Theme) -> LoginScreen {
+        LoginScreen {
+            theme,
+            can_cancel: true,
+            ..Default::default()
+        }
+    }
+
+    /// Create new instance asking for passphrase of saved secrets
+    pub fn unlock(theme: Theme) -> LoginScreen {
+        LoginScreen {
+            theme,
+            unlock: true,
+            ..Default::default()
+        }
+    }
+
     // Login screen view
     pub fn view(&mut self) -> Element<Message> {
-        Column::new()
+        if self.unlock {
+            return Column::new()
+                .padding(16)
+                .spacing(16)
+                .align_items(Align::Center)
+                .push(Text::new("Enter passphrase to unlock saved login"))
+                .push(
+                    TextInput::new(
+                        &mut self.passphrase_state,
+                        "Passphrase",
+                        &self.passphrase,
+                        Message::PassphraseChanged
+                    )
+                    .style(self.theme)
+                    .padding(self.theme.input_padding)
+                    .password()
+                    .on_submit(Message::Unlock)
+                )
+                .push(
+                    Button::new(
+                        &mut self.login_button,
+                        Text::new("Unlock"),
+                    )
+                    .on_press(Message::Unlock)
+                    .style(self.theme)
+                    .padding(16)
+                )
+                .into();
+        }
+
+        let mut column = Column::new()
             .padding(16)
             .spacing(16)
             .align_items(Align::Center)
@@ -73,6 +129,17 @@ impl LoginScreen {
                 .style(self.theme)
                 .padding(self.theme.input_padding)
             )
+            .push(
+                TextInput::new(
+                    &mut self.passphrase_state,
+                    "Passphrase (optional, encrypts saved login)",
+                    &self.passphrase,
+                    Message::PassphraseChanged
+                )
+                .style(self.theme)
+                .padding(self.theme.input_padding)
+                .password()
+            )
             .push(
                 Button::new(
                     &mut self.login_button,
@@ -81,7 +148,19 @@ impl LoginScreen {
                 .on_press(Message::Login)
                 .style(self.theme)
                 .padding(16)
-            )
-            .into()
+            );
+
+        if self.can_cancel {
+            column = column.push(
+                Button::new(
+                    &mut self.cancel_button,
+                    Text::new("Cancel"),
+                )
+                .on_press(Message::CancelLogin)
+                .style(DarkButton {})
+                .padding(16)
+            );
+        }
+        column.into()
     }
 }
\ No newline at end of file
@@ -1,6 +1,8 @@
-use iced::{Align, Button, Column, Container, Element, Length, ProgressBar, Row, Scrollable, Space, Svg, Text, TextInput, button, scrollable, svg, text_input};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use iced::{Align, Button, Column, Container, Element, Length, PickList, ProgressBar, Row, Scrollable, Space, Svg, Text, TextInput, button, pick_list, scrollable, svg, text_input};
 
-use crate::{downloader::{Download, DownloadState}, ui::theme::IconButton};
+use crate::{downloader::{Download, DownloadOverrides, DownloadState, DownloaderEvent, Quality, QueueMove, QueueStats}, spotify::ConnectionState, ui::theme::IconButton};
 
 use super::{Message, Theme};
 
@@ -8,23 +10,107 @@ use super::{Message, Theme};
 pub struct MainScreen {
     theme: Theme,
     pub downloads: Vec<Download>,
+    pub stats: QueueStats,
+    /// Web API rate limit cooldown
+    pub cooldown: Option<Duration>,
+    /// Session state of active account
+    pub connection: ConnectionState,
+    /// Downloads with expanded error details
+    expanded: HashSet<i64>,
+    error_states: HashMap<i64, button::State>,
+    /// Top, up and down buttons of pending rows
+    move_states: HashMap<i64, [button::State; 3]>,
+    export_state: button::State,
+    /// Usernames of all accounts
+    accounts: Vec<String>,
+    active: Option<String>,
+    account_state: pick_list::State<String>,
+    add_account_state: button::State,
+    logout_state: button::State,
 
     pub query: String,
     query_state: text_input::State,
     query_submit: button::State,
+    /// Add with options panel
+    pub show_options: bool,
+    pub add_options: DownloadOverrides,
+    options_state: button::State,
+    add_quality_state: pick_list::State<QualityOption>,
+    add_format_state: pick_list::State<FormatOption>,
+    add_path_state: text_input::State,
+    add_template_state: text_input::State,
+    /// Priority input as typed, parsed into add_options
+    pub add_priority: String,
+    add_priority_state: text_input::State,
     scroll_state: scrollable::State,
     settings_state: button::State
 }
 
 impl MainScreen {
     /// Create new instance
-    pub fn new(theme: Theme) -> MainScreen {
+    pub fn new(theme: Theme, accounts: Vec<String>, active: String) -> MainScreen {
         MainScreen {
             theme,
+            accounts,
+            active: Some(active),
             ..Default::default()
         }
     }
     
+    /// Update downloads from queue event
+    pub fn apply_event(&mut self, event: DownloaderEvent) {
+        match event {
+            DownloaderEvent::Added(downloads) => self.downloads.extend(downloads),
+            DownloaderEvent::StateChanged(id, state) => if let Some(d) = self.downloads.iter_mut().find(|d| d.id == id) {
+                d.state = state;
+            },
+            DownloaderEvent::Progress(id, read, total, speed) => if let Some(d) = self.downloads.iter_mut().find(|d| d.id == id) {
+                d.state = DownloadState::Downloading(read, total, speed);
+            },
+            DownloaderEvent::Removed(id) => self.downloads.retain(|d| d.id != id),
+            DownloaderEvent::Downgraded(id, quality) => if let Some(d) = self.downloads.iter_mut().find(|d| d.id == id) {
+                d.quality = Some(quality);
+            },
+            DownloaderEvent::Reordered(order) => {
+                let positions: HashMap<i64, (usize, i32)> = order.into_iter().enumerate().map(|(i, (id, p))| (id, (i, p))).collect();
+                for d in &mut self.downloads {
+                    if let Some((_, priority)) = positions.get(&d.id) {
+                        d.priority = *priority;
+                    }
+                }
+                self.downloads.sort_by_key(|d| positions.get(&d.id).map(|(i, _)| *i).unwrap_or(usize::MAX));
+            },
+            DownloaderEvent::Stats(stats) => self.stats = stats
+        }
+    }
+
+    /// Overrides for next added item, None if not set
+    pub fn overrides(&self) -> Option<DownloadOverrides> {
+        Some(self.add_options.clone()).filter(|o| self.show_options && !o.is_empty())
+    }
+
+    /// Queue statistics line
+    pub fn summary(&self) -> String {
+        format!(
+            "{} done, {} failed, {} pending | {}/s (avg {}/s) | {} total",
+            self.stats.done, self.stats.failed, self.stats.pending + self.stats.downloading,
+            format_bytes(self.stats.speed), format_bytes(self.stats.average_speed), format_bytes(self.stats.bytes)
+        ) + &match self.stats.downgraded {
+            0 => String::new(),
+            n => format!(" | {} in lower quality", n)
+        } + &match self.stats.duplicates {
+            0 => String::new(),
+            n => format!(" | {} duplicates skipped", n)
+        }
+    }
+
+    /// Expand or collapse error details
+    pub fn toggle_error(&mut self, id: i64) {
+        if !self.expanded.remove(&id) {
+            self.expanded.insert(id);
+        }
+    }
+
     /// iced view
     pub fn view(&mut self) -> Element<Message> {
         let mut downloads = Scrollable::new(&mut self.scroll_state)
@@ -34,13 +120,31 @@ impl MainScreen {
             .height(Length::Fill)
             .align_items(Align::Start);
 
+        // Button states for error rows
+        let list = &self.downloads;
+        self.error_states.retain(|id, _| list.iter().any(|d| d.id == *id));
+        for d in list {
+            if let DownloadState::Error(_) = d.state {
+                self.error_states.entry(d.id).or_insert(button::State::new());
+            }
+        }
+        let mut error_states: HashMap<i64, &mut button::State> = self.error_states.iter_mut().map(|(id, s)| (*id, s)).collect();
+        // Button states for pending rows
+        self.move_states.retain(|id, _| list.iter().any(|d| d.id == *id && d.state == DownloadState::None));
+        for d in list {
+            if d.state == DownloadState::None {
+                self.move_states.entry(d.id).or_insert(Default::default());
+            }
+        }
+        let mut move_states: HashMap<i64, &mut [button::State; 3]> = self.move_states.iter_mut().map(|(id, s)| (*id, s)).collect();
+
         for d in &self.downloads {
             // State text
             let mut error = false;
             let (text, progress) = match d.state {
                 DownloadState::None => ("0%".to_string(), 0.0),
                 DownloadState::Lock => ("0%".to_string(), 0.0),
-                DownloadState::Downloading(r, t) => {
+                DownloadState::Downloading(r, t, _) => {
                     let p = (r as f32 / t as f32) * 100.0;
                     // Sometimes progress can be above 100% due to size being MP3 encoded size
                     if p > 100.0 {
@@ -57,25 +161,71 @@ impl MainScreen {
                 },
             };
 
+            let mut info = Column::new()
+                .push(Text::new(&d.title).size(16))
+                .push(Text::new(&d.subtitle).size(14));
+            if d.priority != 0 {
+                info = info.push(Text::new(format!("Priority {}", d.priority)).size(12));
+            }
+            if let Some(quality) = d.quality {
+                info = info.push(Text::new(format!("Lower quality: {}", quality.to_string())).size(12));
+            }
+            // Speed and ETA
+            if let DownloadState::Downloading(_, _, speed) = d.state {
+                let eta = d.state.eta().map(|e| format!(", {} left", format_duration(e))).unwrap_or_default();
+                info = info.push(Text::new(format!("{}/s{}", format_bytes(speed), eta)).size(12));
+            }
+            // Error details
+            if let (DownloadState::Error(e), true) = (&d.state, self.expanded.contains(&d.id)) {
+                info = info.push(
+                    Text::new(format!(
+                        "{} stage, {}: {}\nTrack: {}{}",
+                        e.stage.to_string(), e.error.kind(), e.error, e.track_id,
+                        if e.retryable { " (retryable)" } else { "" }
+                    ))
+                    .size(12)
+                    .color(self.theme.error)
+                );
+            }
+
+            // Clickable state for errors
+            let state_text = Text::new(&text)
+                .size(28)
+                .color(match error {
+                    true => self.theme.error,
+                    false => self.theme.accent
+                });
+            let state: Element<Message> = match error_states.remove(&d.id) {
+                Some(button_state) => Button::new(button_state, state_text)
+                    .style(IconButton {})
+                    .on_press(Message::ToggleError(d.id))
+                    .padding(0)
+                    .width(Length::Units(80))
+                    .into(),
+                None => state_text.width(Length::Units(80)).into()
+            };
+
+            let mut row = Row::new()
+                .spacing(8)
+                .push(state)
+                .push(info);
+            // Reorder pending
+            if let Some([top, up, down]) = move_states.remove(&d.id) {
+                let mut buttons = Row::new().spacing(4).push(Space::with_width(Length::Fill));
+                for (state, label, direction) in vec![(top, "Top", QueueMove::Top), (up, "Up", QueueMove::Up), (down, "Down", QueueMove::Down)] {
+                    buttons = buttons.push(
+                        Button::new(state, Text::new(label).size(12))
+                        .on_press(Message::MoveDownload(d.id, direction))
+                        .style(self.theme)
+                        .padding(4)
+                    );
+                }
+                row = row.push(buttons.width(Length::Fill));
+            }
+
             downloads = downloads.push(
                 Column::new()
-                .push(Row::new()
-                    .spacing(8)
-                    .push(
-                        Text::new(&text)
-                        .size(28)
-                        .color(match error {
-                            true => self.theme.error,
-                            false => self.theme.accent
-                        })
-                        .width(Length::Units(80))
-                    )
-                    .push(
-                    Column::new()
-                        .push(Text::new(&d.title).size(16))
-                        .push(Text::new(&d.subtitle).size(14))
-                    )
-                )
+                .push(row)
                 // Padding
                 .push(Space::with_height(Length::Units(4)))
                 // Progress
@@ -87,7 +237,7 @@ impl MainScreen {
             );
         }
 
-        Column::new()
+        let mut column = Column::new()
         .padding(16)
         .spacing(8)
         .align_items(Align::Center)
@@ -118,6 +268,41 @@ impl MainScreen {
                 .padding(8)
             )
         )
+        .push(
+            Row::new()
+            .spacing(8)
+            .align_items(Align::Center)
+            .push(
+                PickList::new(
+                    &mut self.account_state,
+                    &self.accounts,
+                    self.active.clone(),
+                    Message::SwitchAccount
+                )
+                .style(self.theme)
+                .width(Length::Fill)
+            )
+            .push(
+                Text::new(self.connection.to_string())
+                .size(14)
+                .color(match self.connection {
+                    ConnectionState::Connected => self.theme.accent,
+                    _ => self.theme.error
+                })
+            )
+            .push(
+                Button::new(&mut self.add_account_state, Text::new("Add account").size(14))
+                .on_press(Message::AddAccount)
+                .style(self.theme)
+                .padding(8)
+            )
+            .push(
+                Button::new(&mut self.logout_state, Text::new("Logout").size(14))
+                .on_press(Message::Logout)
+                .style(self.theme)
+                .padding(8)
+            )
+        )
         .push(
             Space::with_height(Length::Units(8))
         )
@@ -143,11 +328,168 @@ impl MainScreen {
                     .style(self.theme)
                     .padding(8)
                 )
-        )
+                .push(
+                    Button::new(
+                        &mut self.options_state,
+                        Text::new(if self.show_options { "Hide options" } else { "Options" })
+                    )
+                    .on_press(Message::ToggleAddOptions)
+                    .style(self.theme)
+                    .padding(8)
+                )
+        );
+
+        // Add with options
+        if self.show_options {
+            let options = &self.add_options;
+            column = column
+            .push(
+                Row::new()
+                .spacing(8)
+                .push(
+                    PickList::new(
+                        &mut self.add_quality_state,
+                        &QualityOption::ALL[..],
+                        Some(QualityOption(options.quality)),
+                        Message::SetAddQuality
+                    )
+                    .style(self.theme)
+                    .width(Length::Fill)
+                )
+                .push(
+                    PickList::new(
+                        &mut self.add_format_state,
+                        &FormatOption::ALL[..],
+                        Some(FormatOption(options.convert_to_mp3)),
+                        Message::SetAddFormat
+                    )
+                    .style(self.theme)
+                    .width(Length::Fill)
+                )
+            )
+            .push(
+                TextInput::new(
+                    &mut self.add_path_state,
+                    "Download path (default from settings)",
+                    &options.path.as_ref().map(|p| p.to_string_lossy().to_string()).unwrap_or_default(),
+                    Message::AddPathChanged
+                )
+                .style(self.theme)
+                .padding(self.theme.input_padding)
+            )
+            .push(
+                TextInput::new(
+                    &mut self.add_template_state,
+                    "Filename template (default from settings)",
+                    options.filename_template.as_deref().unwrap_or(""),
+                    Message::AddTemplateChanged
+                )
+                .style(self.theme)
+                .padding(self.theme.input_padding)
+            )
+            .push(
+                TextInput::new(
+                    &mut self.add_priority_state,
+                    "Priority, higher is downloaded first (default 0)",
+                    &self.add_priority,
+                    Message::AddPriorityChanged
+                )
+                .style(self.theme)
+                .padding(self.theme.input_padding)
+            );
+        }
+
+        column = column
         .push(Space::with_height(Length::Units(8)))
-        .push(Text::new("Downloads:"))
+        .push(Text::new("Downloads:"));
+
+        let mut summary = Row::new()
+        .spacing(8)
+        .align_items(Align::Center)
+        .push(
+            Text::new(self.summary())
+            .size(14)
+            .width(Length::Fill)
+        );
+        if self.stats.failed > 0 {
+            summary = summary.push(
+                Button::new(&mut self.export_state, Text::new("Export errors").size(14))
+                .on_press(Message::ExportErrors)
+                .style(self.theme)
+                .padding(4)
+            );
+        }
+        column = column.push(summary);
+
+        // Rate limit cooldown
+        if let Some(c) = self.cooldown {
+            column = column.push(
+                Text::new(format!("Rate limited by Spotify, resuming in {}s", c.as_secs() + 1))
+                .size(14)
+                .color(self.theme.error)
+            );
+        }
+
+        column
         .push(Space::with_height(Length::Units(8)))
         .push(downloads)
         .into()
     }
-}
\ No newline at end of file
+}
+
+/// Quality pick list entry, None uses settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QualityOption(pub Option<Quality>);
+
+impl QualityOption {
+    pub const ALL: [QualityOption; 5] = [
+        QualityOption(None),
+        QualityOption(Some(Quality::Q320)),
+        QualityOption(Some(Quality::Q256)),
+        QualityOption(Some(Quality::Q160)),
+        QualityOption(Some(Quality::Q96))
+    ];
+}
+
+impl ToString for QualityOption {
+    fn to_string(&self) -> String {
+        match self.0 {
+            Some(q) => q.to_string(),
+            None => "Default quality".to_string()
+        }
+    }
+}
+
+/// Format pick list entry, MP3 conversion or None to use settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatOption(pub Option<bool>);
+
+impl FormatOption {
+    pub const ALL: [FormatOption; 3] = [FormatOption(None), FormatOption(Some(false)), FormatOption(Some(true))];
+}
+
+impl ToString for FormatOption {
+    fn to_string(&self) -> String {
+        match self.0 {
+            Some(true) => "MP3",
+            Some(false) => "Original format",
+            None => "Default format"
+        }.to_string()
+    }
+}
+
+/// Format byte count to human readable string
+fn format_bytes(bytes: usize) -> String {
+    match bytes {
+        b if b >= 1024 * 1024 * 1024 => format!("{:.2} GB", b as f64 / (1024.0 * 1024.0 * 1024.0)),
+        b if b >= 1024 * 1024 => format!("{:.1} MB", b as f64 / (1024.0 * 1024.0)),
+        b if b >= 1024 => format!("{:.0} KB", b as f64 / 1024.0),
+        b => format!("{} B", b)
+    }
+}
+
+/// Format duration as m:ss
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
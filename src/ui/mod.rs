@@ -1,15 +1,18 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 use iced::window::Icon;
 use iced::{Application, Clipboard, Command, Container, Element, Length, Subscription, Text, executor, time};
 
-use crate::downloader::{Download, DownloaderConfig, Quality};
+use crate::config::Overrides;
+use crate::downloader::{Download, DownloadOverrides, DownloaderConfig, DownloaderEvent, Quality, QueueMove, TagPolicy};
 use crate::error::SpotifyError;
+use crate::logger;
 
 pub use theme::Theme;
+pub use backend::Settings;
 use backend::Backend;
 use login_screen::LoginScreen;
-use main_screen::MainScreen;
+use main_screen::{FormatOption, MainScreen, QualityOption};
 use settings_screen::SettingsScreen;
 
 mod theme;
@@ -52,15 +55,33 @@ pub enum Message {
     PasswordChanged(String),
     ClientIdChanged(String),
     ClientSecretChanged(String),
+    PassphraseChanged(String),
     BackendLoad(Result<Backend, SpotifyError>),
     Login,
+    Unlock,
 
     QueryChanged(String),
     QuerySubmit, 
+    ToggleAddOptions,
+    SetAddQuality(QualityOption),
+    SetAddFormat(FormatOption),
+    AddPathChanged(String),
+    AddTemplateChanged(String),
+    AddPriorityChanged(String),
     GetDownloadQueue,
     DownloadQueue(Vec<Download>),
+    DownloaderEvent(DownloaderEvent),
+    ToggleError(i64),
+    MoveDownload(i64, QueueMove),
+    ExportErrors,
+    Tick,
     OpenSettings,
-    Settings(DownloaderConfig),
+    Settings((DownloaderConfig, bool)),
+    SwitchAccount(String),
+    AddAccount,
+    ShowLogin(()),
+    CancelLogin,
+    Logout,
 
     SetDownloadPath(String),
     SetFilenameTemplate(String),
@@ -69,6 +90,11 @@ pub enum Message {
     SetQuality(Quality),
     SetSeparator(String),
     SetID3v24(bool),
+    SetTagPolicy(TagPolicy),
+    SetCacheOnDisk(bool),
+    SetPerAccount(bool),
+    CopyLogs,
+    LogsLoaded(String),
     DiscardSettings,
     SaveSettings,
     CloseSettings(())
@@ -77,7 +103,11 @@ pub enum Message {
 struct App {
     theme: Theme,
     screen: Screens,
-    backend: Option<Backend>
+    backend: Option<Backend>,
+    /// Items from command line, queued once logged in
+    startup_queue: Vec<(String, Option<DownloadOverrides>)>,
+    /// Last stats line printed to stderr, None unless items were added on command line
+    printed_stats: Option<String>
 }
 
 impl App {
@@ -85,6 +115,12 @@ impl App {
     fn backend(&mut self) -> Backend {
         self.backend.as_ref().unwrap().clone()
     }
+
+    /// Create main screen with account list
+    fn main_screen(&self) -> MainScreen {
+        let backend = self.backend.as_ref().unwrap();
+        MainScreen::new(self.theme, backend.accounts.clone(), backend.active.clone())
+    }
 }
 
 impl Application for App {
@@ -94,13 +130,20 @@ impl Application for App {
 
     /// Create new instance, connect to backend
     fn new(_flags: ()) -> (Self, Command<Message>) {
+        let startup_queue = Overrides::from_env().map(|o| o.queue).unwrap_or_default();
+        let printed_stats = match startup_queue.is_empty() {
+            true => None,
+            false => Some(String::new())
+        };
         (
             App { 
                 theme: Theme::default(),
                 screen: Screens::Loading,
-                backend: None
+                backend: None,
+                startup_queue,
+                printed_stats
             }, 
-            Command::perform(Backend::try_load(), Message::BackendLoad)
+            Command::perform(Backend::try_load(None), Message::BackendLoad)
         )
     }
 
@@ -109,13 +152,16 @@ impl Application for App {
     }
     
     fn subscription(&self) -> Subscription<Self::Message> {
-        match self.backend.is_some() {
-            false => Subscription::none(),
-            true => time::every(Duration::from_millis(200)).map(|_| Message::GetDownloadQueue)
+        match &self.backend {
+            None => Subscription::none(),
+            Some(backend) => Subscription::batch(vec![
+                backend.events(),
+                time::every(Duration::from_secs(1)).map(|_| Message::Tick)
+            ])
         }
     }
 
-    fn update(&mut self, message: Self::Message, _clipboard: &mut Clipboard) -> Command<Self::Message> {
+    fn update(&mut self, message: Self::Message, clipboard: &mut Clipboard) -> Command<Self::Message> {
         match message {
             Message::Empty(_) => {}
 
@@ -123,9 +169,18 @@ impl Application for App {
 
             Message::BackendLoad(Ok(backend)) => {
                 self.backend = Some(backend);
-                self.screen = Screens::Main { screen: MainScreen::new(self.theme) };
+                self.screen = Screens::Main { screen: self.main_screen() };
+                let mut commands = vec![Command::perform(self.backend().get_downloads(), Message::DownloadQueue)];
+                for (uri, overrides) in std::mem::take(&mut self.startup_queue) {
+                    commands.push(Command::perform(self.backend().add_uri(uri, overrides), Message::Empty));
+                }
+                return Command::batch(commands);
             }
-            Message::BackendLoad(Err(_)) => {
+            Message::BackendLoad(Err(SpotifyError::Locked)) => {
+                self.screen = Screens::Login { screen: LoginScreen::unlock(self.theme) };
+            }
+            Message::BackendLoad(Err(e)) => {
+                warn!("Failed loading saved login: {}", e);
                 self.screen = Screens::Login { screen: LoginScreen::new(self.theme) };
             }
 
@@ -143,10 +198,22 @@ impl Application for App {
             Message::ClientSecretChanged(v) => if let Screens::Login { screen, .. } = &mut self.screen {
                 screen.client_secret = v;
             },
+            Message::PassphraseChanged(v) => if let Screens::Login { screen, .. } = &mut self.screen {
+                screen.passphrase = v;
+            },
             Message::Login => if let Screens::Login {screen, ..} = &mut self.screen {
                 let screen = screen.clone();
                 self.screen = Screens::Loading;
-                return Command::perform(Backend::login(screen.username, screen.password, screen.client_id, screen.client_secret), Message::BackendLoad);
+                return Command::perform(Backend::login(screen.username, screen.password, screen.client_id, screen.client_secret, screen.passphrase), Message::BackendLoad);
+            }
+            Message::CancelLogin => {
+                self.screen = Screens::Loading;
+                return Command::perform(Backend::try_load(None), Message::BackendLoad);
+            }
+            Message::Unlock => if let Screens::Login {screen, ..} = &mut self.screen {
+                let passphrase = screen.passphrase.clone();
+                self.screen = Screens::Loading;
+                return Command::perform(Backend::try_load(Some(passphrase)), Message::BackendLoad);
             }
 
             // MAIN SCREEN
@@ -156,7 +223,30 @@ impl Application for App {
             }
             Message::QuerySubmit => if let Screens::Main { screen } = &mut self.screen {
                 let uri = screen.query.clone();
-                return Command::perform(self.backend().add_uri(uri), Message::Empty);
+                let overrides = screen.overrides();
+                return Command::perform(self.backend().add_uri(uri, overrides), Message::Empty);
+            }
+            Message::ToggleAddOptions => if let Screens::Main { screen } = &mut self.screen {
+                screen.show_options = !screen.show_options;
+            }
+            Message::SetAddQuality(q) => if let Screens::Main { screen } = &mut self.screen {
+                screen.add_options.quality = q.0;
+            }
+            Message::SetAddFormat(f) => if let Screens::Main { screen } = &mut self.screen {
+                screen.add_options.convert_to_mp3 = f.0;
+            }
+            Message::AddPathChanged(p) => if let Screens::Main { screen } = &mut self.screen {
+                screen.add_options.path = Some(p).filter(|p| !p.is_empty()).map(PathBuf::from);
+            }
+            Message::AddTemplateChanged(t) => if let Screens::Main { screen } = &mut self.screen {
+                screen.add_options.filename_template = Some(t).filter(|t| !t.is_empty());
+            }
+            Message::AddPriorityChanged(p) => if let Screens::Main { screen } = &mut self.screen {
+                // Allow typing minus sign
+                if p.is_empty() || p == "-" || p.parse::<i32>().is_ok() {
+                    screen.add_options.priority = p.parse().ok();
+                    screen.add_priority = p;
+                }
             }
             Message::GetDownloadQueue => if let Screens::Main { .. } = &mut self.screen {
                 return Command::perform(self.backend().get_downloads(), Message::DownloadQueue);
@@ -164,11 +254,57 @@ impl Application for App {
             Message::DownloadQueue(q) => if let Screens::Main { screen } = &mut self.screen {
                 screen.downloads = q;
             }
+            Message::DownloaderEvent(e) => if let Screens::Main { screen } = &mut self.screen {
+                screen.apply_event(e);
+            }
+            Message::ToggleError(id) => if let Screens::Main { screen } = &mut self.screen {
+                screen.toggle_error(id);
+            }
+            Message::MoveDownload(id, direction) => {
+                return Command::perform(self.backend().move_download(id, direction), Message::Empty);
+            }
+            Message::ExportErrors => {
+                return Command::perform(self.backend().export_errors(), Message::Empty);
+            }
+            Message::Tick => if let Screens::Main { screen } = &mut self.screen {
+                screen.cooldown = self.backend.as_ref().and_then(|b| b.rate_limit());
+                if let Some(connection) = self.backend.as_ref().map(|b| b.connection()) {
+                    screen.connection = connection;
+                }
+                // Progress of items added on command line
+                if let Some(printed) = &mut self.printed_stats {
+                    let summary = screen.summary();
+                    if summary != *printed {
+                        eprintln!("{}", summary);
+                        *printed = summary;
+                    }
+                }
+            }
             Message::OpenSettings => {
                 return Command::perform(self.backend().get_config(), Message::Settings)
             }
-            Message::Settings(s) => {
-                self.screen = Screens::Settings { screen: SettingsScreen::new(self.theme, s) };
+            Message::Settings((s, per_account)) => {
+                let account_type = self.backend.as_ref().unwrap().account_type;
+                self.screen = Screens::Settings { screen: SettingsScreen::new(self.theme, s, per_account, account_type) };
+            }
+
+            // ACCOUNTS
+
+            Message::SwitchAccount(username) => if self.backend.as_ref().map(|b| b.active != username).unwrap_or(false) {
+                let backend = self.backend.take().unwrap();
+                self.screen = Screens::Loading;
+                return Command::perform(backend.switch_account(username), Message::BackendLoad);
+            }
+            Message::AddAccount => if let Some(backend) = self.backend.take() {
+                self.screen = Screens::Loading;
+                return Command::perform(backend.shutdown(), Message::ShowLogin);
+            }
+            Message::ShowLogin(_) => {
+                self.screen = Screens::Login { screen: LoginScreen::add_account(self.theme) };
+            }
+            Message::Logout => if let Some(backend) = self.backend.take() {
+                self.screen = Screens::Loading;
+                return Command::perform(backend.logout(), Message::BackendLoad);
             }
 
 
@@ -195,15 +331,33 @@ impl Application for App {
             Message::SetSeparator(v) => if let Screens::Settings { screen } = &mut self.screen {
                 screen.config.separator = v;
             }
+            Message::SetTagPolicy(v) => if let Screens::Settings { screen } = &mut self.screen {
+                screen.config.tag_policy = v;
+            }
+            Message::SetCacheOnDisk(v) => if let Screens::Settings { screen } = &mut self.screen {
+                screen.config.cache_on_disk = v;
+            }
+            Message::SetPerAccount(v) => if let Screens::Settings { screen } = &mut self.screen {
+                screen.per_account = v;
+            }
+            Message::CopyLogs => {
+                return Command::perform(logger::bug_report(Settings::get_folder()), Message::LogsLoaded);
+            }
+            Message::LogsLoaded(logs) => {
+                clipboard.write(logs);
+            }
             Message::DiscardSettings => {
-                self.screen = Screens::Main { screen: MainScreen::new(self.theme) };
+                self.screen = Screens::Main { screen: self.main_screen() };
+                return Command::perform(self.backend().get_downloads(), Message::DownloadQueue);
             }
             Message::SaveSettings => if let Screens::Settings { screen } = &mut self.screen {
                 let config = screen.config.clone();
-                return Command::perform(self.backend().save_config(config), Message::CloseSettings);
+                let per_account = screen.per_account;
+                return Command::perform(self.backend().save_config(config, per_account), Message::CloseSettings);
             }
             Message::CloseSettings(_) => {
-                self.screen = Screens::Main { screen: MainScreen::new(self.theme) };
+                self.screen = Screens::Main { screen: self.main_screen() };
+                return Command::perform(self.backend().get_downloads(), Message::DownloadQueue);
             }
 
         }
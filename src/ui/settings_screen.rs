@@ -1,31 +1,38 @@
 use iced::{Button, Checkbox, Column, Element, Length, PickList, Row, Slider, VerticalAlignment, HorizontalAlignment, Space, Text, TextInput, button, pick_list, slider, text_input};
 
-use crate::{downloader::{DownloaderConfig, Quality}, ui::theme::DarkButton};
+use crate::{downloader::{DownloaderConfig, Quality, TagPolicy}, spotify::AccountType, ui::theme::DarkButton};
 
 use super::{Message, Theme};
 
 pub struct SettingsScreen {
     theme: Theme,
     pub config: DownloaderConfig,
+    /// Save only for active account
+    pub per_account: bool,
+    account_type: AccountType,
 
     path: text_input::State,
     filename_template: text_input::State,
     concurrent_downloads: slider::State,
     quality: pick_list::State<Quality>,
     separator: text_input::State,
+    tag_policy: pick_list::State<TagPolicy>,
+    copy_logs_button: button::State,
     discard_button: button::State,
     save_button: button::State
 }
 
 impl SettingsScreen {
-    pub fn new(theme: Theme, config: DownloaderConfig) -> SettingsScreen {
+    pub fn new(theme: Theme, config: DownloaderConfig, per_account: bool, account_type: AccountType) -> SettingsScreen {
         SettingsScreen {
-            theme, config,
+            theme, config, per_account, account_type,
             path: text_input::State::default(),
             filename_template: text_input::State::default(),
             concurrent_downloads: slider::State::default(),
             quality: pick_list::State::default(),
             separator: text_input::State::default(),
+            tag_policy: pick_list::State::default(),
+            copy_logs_button: button::State::default(),
             discard_button: button::State::default(),
             save_button: button::State::default(),
         }
@@ -104,6 +111,19 @@ impl SettingsScreen {
                 .style(self.theme)
                 .padding(self.theme.input_padding)
             )
+            .push(Space::with_width(Length::Units(8)))
+            .push({
+                // Effective max quality of account
+                let max = self.account_type.max_quality();
+                Text::new(format!("Max for {} account: {}", self.account_type.to_string(), max.to_string()))
+                .size(14)
+                .color(match self.config.quality.cap(max) != self.config.quality {
+                    true => self.theme.error,
+                    false => self.theme.text
+                })
+                .height(Length::Fill)
+                .vertical_alignment(VerticalAlignment::Center)
+            })
             .height(Length::Units(28))
         )
         .push(
@@ -116,6 +136,25 @@ impl SettingsScreen {
             .style(self.theme)
             .padding(self.theme.input_padding)
         )
+        .push(
+            Row::new()
+            .push(
+                Text::new("Existing Tags: ")
+                .height(Length::Fill)
+                .vertical_alignment(VerticalAlignment::Center)
+            )
+            .push(
+                PickList::new(
+                    &mut self.tag_policy,
+                    &TagPolicy::ALL[..],
+                    Some(self.config.tag_policy),
+                    Message::SetTagPolicy
+                )
+                .style(self.theme)
+                .padding(self.theme.input_padding)
+            )
+            .height(Length::Units(28))
+        )
         .push(
             Checkbox::new(
                 self.config.id3v24,
@@ -124,6 +163,31 @@ impl SettingsScreen {
             )
             .style(self.theme)
         )
+        .push(
+            Checkbox::new(
+                self.config.cache_on_disk,
+                "Cache metadata and covers on disk",
+                Message::SetCacheOnDisk
+            )
+            .style(self.theme)
+        )
+        .push(
+            Checkbox::new(
+                self.per_account,
+                "Use these settings only for current account",
+                Message::SetPerAccount
+            )
+            .style(self.theme)
+        )
+        .push(
+            Button::new(
+                &mut self.copy_logs_button,
+                Text::new("Copy logs for bug report").horizontal_alignment(HorizontalAlignment::Center)
+            )
+            .on_press(Message::CopyLogs)
+            .style(DarkButton {})
+            .width(Length::Fill)
+        )
         .push(
             Space::with_height(Length::Units(16))
         )
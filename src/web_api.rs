@@ -0,0 +1,118 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use reqwest::{Client, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::error::SpotifyError;
+
+/// Web API endpoints, can be pointed to a local mock server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ApiUrls {
+    pub api: String,
+    pub token: String
+}
+
+impl Default for ApiUrls {
+    fn default() -> Self {
+        ApiUrls {
+            api: "https://api.spotify.com/v1".to_string(),
+            token: "https://accounts.spotify.com/api/token".to_string()
+        }
+    }
+}
+
+/// Minimal Spotify Web API client using client credentials
+#[derive(Debug, Clone)]
+pub struct WebApi {
+    client: Client,
+    client_id: String,
+    client_secret: String,
+    urls: ApiUrls,
+    // Access token and expiry
+    token: Arc<Mutex<Option<(String, Instant)>>>
+}
+
+impl WebApi {
+    /// Create new instance, client can use proxy
+    pub fn new(client_id: &str, client_secret: &str, urls: ApiUrls, client: Client) -> WebApi {
+        WebApi {
+            client,
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            urls,
+            token: Arc::new(Mutex::new(None))
+        }
+    }
+
+    /// Get HTTP client
+    pub fn client(&self) -> Client {
+        self.client.clone()
+    }
+
+    /// Get valid access token, refresh if expired
+    async fn token(&self) -> Result<String, SpotifyError> {
+        let mut lock = self.token.lock().await;
+        if let Some((token, expires)) = &*lock {
+            if *expires > Instant::now() {
+                return Ok(token.to_string());
+            }
+        }
+
+        let res = self.client.post(&self.urls.token)
+            .basic_auth(&self.client_id, Some(&self.client_secret))
+            .form(&[("grant_type", "client_credentials")])
+            .send().await?;
+        if !res.status().is_success() {
+            return Err(SpotifyError::AuthenticationError);
+        }
+        let token: TokenResponse = serde_json::from_slice(&res.bytes().await?)?;
+        // Refresh a bit before expiry
+        let expires = Instant::now() + Duration::from_secs(token.expires_in.saturating_sub(60));
+        *lock = Some((token.access_token.to_string(), expires));
+        Ok(token.access_token)
+    }
+
+    /// GET request to API, path is relative to base URL
+    pub async fn get<T: DeserializeOwned>(&self, path: String, query: Vec<(&'static str, String)>) -> Result<T, SpotifyError> {
+        let token = self.token().await?;
+        self.request(path, query, token).await
+    }
+
+    /// GET request with user access token, for user specific endpoints
+    pub async fn get_as_user<T: DeserializeOwned>(&self, path: String, token: String) -> Result<T, SpotifyError> {
+        self.request(path, vec![], token).await
+    }
+
+    async fn request<T: DeserializeOwned>(&self, path: String, query: Vec<(&'static str, String)>, token: String) -> Result<T, SpotifyError> {
+        let res = self.client.get(&format!("{}/{}", self.urls.api.trim_end_matches('/'), path))
+            .bearer_auth(token)
+            .query(&query)
+            .send().await?;
+
+        match res.status() {
+            s if s.is_success() => Ok(serde_json::from_slice(&res.bytes().await?)?),
+            StatusCode::TOO_MANY_REQUESTS => {
+                let retry_after = res.headers().get("retry-after")
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.parse().ok());
+                Err(SpotifyError::RateLimited(retry_after))
+            }
+            StatusCode::UNAUTHORIZED => {
+                // Force token refresh on next request
+                *self.token.lock().await = None;
+                Err(SpotifyError::AuthenticationError)
+            }
+            StatusCode::NOT_FOUND => Err(SpotifyError::Unavailable),
+            s => Err(SpotifyError::ASpotify(format!("{} {}", s, res.text().await.unwrap_or_default())))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64
+}